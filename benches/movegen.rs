@@ -1,5 +1,6 @@
 use cold_clear_2::data::{Board, Piece};
 use cold_clear_2::movegen::find_moves;
+use cold_clear_2::rules::GameRules;
 use criterion::{criterion_group, criterion_main, Criterion};
 
 const PIECES: [Piece; 7] = [
@@ -14,8 +15,11 @@ const PIECES: [Piece; 7] = [
 
 fn bench_movegen(c: &mut Criterion, name: &str, board: Board) {
     let mut group = c.benchmark_group(name);
+    let rules = GameRules::default();
     for p in PIECES {
-        group.bench_function(format!("{:?}", p), |b| b.iter(|| find_moves(&board, p)));
+        group.bench_function(format!("{:?}", p), |b| {
+            b.iter(|| find_moves(&board, p, &rules))
+        });
     }
 }
 
@@ -24,54 +28,48 @@ fn bench(c: &mut Criterion) {
 
     // v115@egA8IeC8FeE8DeF8CeH8BeH8CeH8AeD8JeAgH
     #[rustfmt::skip]
-    bench_movegen(c, "tspin", Board {
-        cols: [
-            0b00111111,
-            0b00111111,
-            0b00011111,
-            0b00000111,
-            0b00000001,
-            0b00000000,
-            0b00001101,
-            0b00011111,
-            0b00111111,
-            0b11111111,
-        ]
-    });
+    bench_movegen(c, "tspin", Board::from_cols(&[
+        0b00111111,
+        0b00111111,
+        0b00011111,
+        0b00000111,
+        0b00000001,
+        0b00000000,
+        0b00001101,
+        0b00011111,
+        0b00111111,
+        0b11111111,
+    ]));
 
     // v115@LgB8HeD8BeH8CeI8AeH8BeH8CeH8AeI8AeH8AeD8Je?AgH
     #[rustfmt::skip]
-    bench_movegen(c, "dtd", Board {
-        cols: [
-            0b111111111,
-            0b111111111,
-            0b011111111,
-            0b011111111,
-            0b000111111,
-            0b000100110,
-            0b010000001,
-            0b011110111,
-            0b011111111,
-            0b011111111,
-        ]
-    });
+    bench_movegen(c, "dtd", Board::from_cols(&[
+        0b111111111,
+        0b111111111,
+        0b011111111,
+        0b011111111,
+        0b000111111,
+        0b000100110,
+        0b010000001,
+        0b011110111,
+        0b011111111,
+        0b011111111,
+    ]));
 
     // v115@vfH8BeH8IeA8IeH8BeH8BeB8HeB8HeB8BeH8BeH8Ie?A8SeAgH
     #[rustfmt::skip]
-    bench_movegen(c, "terrible", Board {
-        cols: [
-            0b000011111111,
-            0b000011000000,
-            0b110011000000,
-            0b110011001100,
-            0b110011001100,
-            0b110011001100,
-            0b110011001100,
-            0b110000001100,
-            0b110000001100,
-            0b111111111100,
-        ]
-    });
+    bench_movegen(c, "terrible", Board::from_cols(&[
+        0b000011111111,
+        0b000011000000,
+        0b110011000000,
+        0b110011001100,
+        0b110011001100,
+        0b110011001100,
+        0b110011001100,
+        0b110000001100,
+        0b110000001100,
+        0b111111111100,
+    ]));
 }
 
 criterion_group!(benchmark, bench);
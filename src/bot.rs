@@ -2,26 +2,90 @@ use std::collections::VecDeque;
 use std::sync::Arc;
 
 use enum_dispatch::enum_dispatch;
+use enum_map::EnumMap;
+use enumset::EnumSet;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
-use crate::data::{GameState, Piece, Placement};
+pub use crate::dag::SelectionPolicy;
+use crate::dag::{ChildData, Dag, Evaluation, UnknownPiece};
+use crate::data::{Board, GameState, GarbageQueue, Piece, Placement, PlacementInfo};
+use crate::movegen::{find_moves, Input, MoveCost, Path, Paths};
+use crate::rules::{GameRules, HoldMode};
 
+mod book;
+mod dag_mode;
+mod dig;
+mod features;
 mod freestyle;
+mod pc;
+mod sprint;
+mod ultra;
 
+use self::book::Book;
+use self::dag_mode::DagMode;
+use self::dig::Dig;
+pub use self::dig::DigProgress;
 use self::freestyle::Freestyle;
+use self::pc::PerfectClear;
+use self::sprint::Sprint;
+use self::ultra::Ultra;
 
 pub struct Bot {
     options: BotOptions,
     current: GameState,
     queue: VecDeque<Piece>,
+    progress: Progress,
     mode: ModeEnum,
 }
 
+/// What the bot has played since it was started, which outlives any one search.
+#[derive(Clone, Copy, Debug, Default)]
+struct Progress {
+    pieces: u32,
+    lines_cleared: u32,
+    garbage_cleared: u32,
+    /// Frames spent on the inputs of the bot's moves.
+    frames: u32,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct BotConfig {
     pub freestyle_weights: freestyle::Weights,
     pub freestyle_exploitation: f64,
+    /// How the search picks the moves to look further into.
+    #[serde(default)]
+    pub selection: SelectionPolicy,
+    /// Number of search threads, read when the bot is launched. Defaults to the available
+    /// parallelism.
+    #[serde(default)]
+    pub threads: Option<usize>,
+    /// Number of candidate moves to report with each suggestion.
+    #[serde(default)]
+    pub candidates: usize,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub book: book::Config,
+    #[serde(default)]
+    pub dig: dig::Config,
+    #[serde(default)]
+    pub perfect_clear: pc::Config,
+    #[serde(default)]
+    pub sprint: sprint::Config,
+    #[serde(default)]
+    pub ultra: ultra::Config,
+    #[serde(default)]
+    pub rules: GameRules,
+}
+
+impl BotConfig {
+    pub fn worker_threads(&self) -> usize {
+        self.threads
+            .or_else(|| std::thread::available_parallelism().ok().map(Into::into))
+            .unwrap_or(1)
+            .max(1)
+    }
 }
 
 impl Default for BotConfig {
@@ -32,6 +96,88 @@ impl Default for BotConfig {
     }
 }
 
+/// How much thinking the bot does for each move. Times are in milliseconds and are counted
+/// from the previous move.
+///
+/// The search stops once `min_time` has passed and either `nodes` or `time` has been reached, or
+/// once `max_time` has passed, whichever comes first. Without any limits the bot keeps thinking
+/// until it is asked for a move.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(default)]
+pub struct Limits {
+    pub nodes: Option<u64>,
+    pub time: Option<u64>,
+    pub min_time: Option<u64>,
+    pub max_time: Option<u64>,
+    /// Megabytes the search tree may use. When it grows past this, the tree is pruned down to the
+    /// most promising lines, and the search stops if that doesn't free enough.
+    pub memory: Option<u64>,
+    /// Send a suggestion without being asked when the search stops.
+    pub auto_suggest: bool,
+}
+
+/// A move the bot is considering, with what the search thinks of it.
+#[derive(Serialize, Clone, Debug)]
+pub struct Candidate {
+    #[serde(rename = "move")]
+    pub mv: Placement,
+    pub eval: f64,
+    /// Number of times the search passed through the move.
+    pub visits: u32,
+    /// Number of positions expanded after the move.
+    pub expansions: u32,
+    /// The placements the bot expects to follow the move, as far as the queue is known.
+    pub pv: Vec<Placement>,
+}
+
+/// A step of the line the bot expects to be played.
+#[derive(Serialize, Clone, Debug)]
+pub struct PlannedMove {
+    pub piece: Piece,
+    /// Whether the piece is a guess, because it isn't in the queue yet.
+    pub speculated: bool,
+    #[serde(rename = "move")]
+    pub mv: Placement,
+    pub eval: f64,
+    /// The board after the move.
+    pub board: Board,
+}
+
+/// A position in an export of the search tree.
+#[derive(Serialize, Clone, Debug)]
+pub struct TreeNode {
+    pub board: Board,
+    pub eval: f64,
+    pub visits: u32,
+    pub expansions: u32,
+    pub children: Vec<TreeEdge>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TreeEdge {
+    pub piece: Piece,
+    pub speculated: bool,
+    #[serde(rename = "move")]
+    pub mv: Placement,
+    pub eval: f64,
+    /// Number of selections that went through the move.
+    pub visits: u32,
+    pub node: TreeNode,
+}
+
+/// How the bot dealt with a move or position it wasn't prepared for.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Recovery {
+    /// The search goes on from a position other than the one it was expecting, keeping what it
+    /// found about the positions that can still be reached.
+    Rerooted,
+    /// The bot couldn't place the position in its search, and started a new one. After a move
+    /// that doesn't fit the queue the bot knows, the piece that was used is a guess, and the
+    /// frontend should resync to be sure.
+    Rebuilt,
+}
+
 #[derive(Debug)]
 pub struct BotOptions {
     pub speculate: bool,
@@ -40,19 +186,60 @@ pub struct BotOptions {
 
 #[enum_dispatch]
 enum ModeEnum {
-    Freestyle,
+    Book,
+    Dig(DagMode<Dig>),
+    Freestyle(DagMode<Freestyle>),
+    PerfectClear,
+    Sprint(DagMode<Sprint>),
+    Ultra(DagMode<Ultra>),
 }
 
 #[enum_dispatch(ModeEnum)]
 trait Mode {
-    fn advance(&mut self, options: &BotOptions, mv: Placement) -> Option<ModeSwitch>;
+    /// Plays `mv`, failing if the mode can't follow it and has to be rebuilt.
+    fn advance(
+        &mut self,
+        options: &BotOptions,
+        mv: Placement,
+        held: bool,
+    ) -> Result<Option<ModeSwitch>, UnknownPiece>;
     fn new_piece(&mut self, options: &BotOptions, piece: Piece);
     fn suggest(&self, options: &BotOptions) -> Vec<Placement>;
-    fn do_work(&self, options: &BotOptions) -> Statistics;
+    fn candidates(&self, options: &BotOptions, count: usize) -> Vec<Candidate>;
+    fn plan(&self, options: &BotOptions, depth: usize) -> Vec<PlannedMove>;
+    fn tree(&self, options: &BotOptions, depth: usize, breadth: usize) -> TreeNode;
+    fn do_work(&self, options: &BotOptions, progress: &Progress) -> Statistics;
+    /// Whether playing `mv` keeps what the search found, see [`Dag::is_expected`].
+    fn is_expected(&self, mv: Placement) -> bool;
+    /// Whether `state` is a position the search reached `depth` moves from the current one.
+    fn contains(&self, depth: usize, state: &GameState) -> bool;
+    /// Continues from `state`, which must be a position the search reached `depth` moves from
+    /// the current one.
+    fn reroot(
+        &mut self,
+        options: &BotOptions,
+        depth: usize,
+        state: GameState,
+    ) -> Option<ModeSwitch>;
+    /// Bytes used by the search tree.
+    fn memory(&self) -> usize;
+    /// Shrinks the search tree, see [`Dag::compact`].
+    fn compact(&mut self, options: &BotOptions, keep: usize);
+
+    /// Asks calls to `do_work` that may take a long time to return early.
+    fn interrupt(&self) {}
+
+    fn perfect_clear_probability(&self) -> Option<f64> {
+        None
+    }
 }
 
 enum ModeSwitch {
+    Dig,
     Freestyle,
+    PerfectClear,
+    Sprint,
+    Ultra,
 }
 
 impl Bot {
@@ -60,17 +247,137 @@ impl Bot {
         Bot {
             current: root,
             queue: queue.iter().copied().collect(),
-            mode: Freestyle::new(&options, root, queue).into(),
+            progress: Progress::default(),
+            mode: initial_mode(&options, root, queue),
             options,
         }
     }
 
-    pub fn advance(&mut self, mv: Placement) {
+    /// Plays `mv`, returning how the bot recovered if it wasn't a move the bot was prepared for.
+    pub fn advance(&mut self, mv: Placement) -> Option<Recovery> {
         puffin::profile_function!();
-        self.current.advance(self.queue.pop_front().unwrap(), mv);
-        if let Some(to) = self.mode.advance(&self.options, mv) {
-            self.switch(to);
+        let piece = mv.location.piece;
+        let Some(next) = self.queue.front().copied() else {
+            return Some(self.restart(mv));
+        };
+        let held = self.current.held_by(mv, next);
+        let playable = piece == next || piece == self.current.reserve;
+        if !playable || held && !self.current.can_hold(&self.options.config.rules) {
+            return Some(self.restart(mv));
+        }
+        let recovery = match self.mode.is_expected(mv) {
+            true => None,
+            false => Some(Recovery::Rerooted),
+        };
+
+        let frames = self.path(mv).map_or(0, |path| path.frames);
+        self.queue.pop_front();
+        let info = self
+            .current
+            .advance(&self.options.config.rules, next, mv, held);
+        self.progress.record(&info, frames);
+        match self.mode.advance(&self.options, mv, held) {
+            Ok(Some(to)) => self.switch(to),
+            Ok(None) => {}
+            Err(UnknownPiece) => {
+                // the search lost track of the queue, so start over from where the game is
+                let queue = self.queue.make_contiguous();
+                self.mode = initial_mode(&self.options, self.current, queue);
+                return Some(Recovery::Rebuilt);
+            }
+        }
+        recovery
+    }
+
+    /// Brings the bot in line with the position the frontend says the game is in. If the search
+    /// reached that position using the queue the bot knows, it goes on from there, and otherwise
+    /// it starts over. Returns how the bot recovered if the position wasn't the current one.
+    pub fn resync(&mut self, mut state: GameState, queue: &[Piece]) -> Option<Recovery> {
+        puffin::profile_function!();
+        let rules = &self.options.config.rules;
+        // frontends only say whether there is a back-to-back, not how long the chain is
+        let chains = match state.back_to_back {
+            0 => 0..=0,
+            _ => 1..=rules.attack.table().max_back_to_back_chain(),
+        };
+        if state.back_to_back > 0 && self.current.back_to_back > 0 {
+            state.back_to_back = self.current.back_to_back;
+        }
+        // nor whether hold is locked
+        let hold_locks = match rules.hold == HoldMode::Restricted && !state.hold_empty {
+            true => vec![false, true],
+            false => vec![false],
+        };
+        // and garbage rows are only known if the frontend marks them, so without any marked
+        // every row at the bottom with something in it might be garbage
+        let garbage_rows = match state.garbage_rows {
+            0 => {
+                let occupied = state.board.cols().iter().fold(0, |rows, &col| rows | col);
+                0..=occupied.trailing_ones().min(40) as u8
+            }
+            rows => rows..=rows,
+        };
+        let mut variants = vec![];
+        for back_to_back in std::iter::once(state.back_to_back).chain(chains) {
+            for &hold_locked in &hold_locks {
+                for garbage_rows in garbage_rows.clone() {
+                    variants.push(GameState {
+                        back_to_back,
+                        hold_locked,
+                        garbage_rows,
+                        ..state
+                    });
+                }
+            }
+        }
+
+        let known: Vec<Piece> = self.queue.iter().copied().collect();
+        let found = (0..=known.len())
+            .filter(|&depth| queue.starts_with(&known[depth..]))
+            .find_map(|depth| {
+                let bag = match self.options.speculate {
+                    true => state.bag,
+                    // without a bag randomizer the bag is only bookkeeping, so use the search's
+                    false => known[..depth]
+                        .iter()
+                        .fold(self.current.bag, |mut bag, &piece| {
+                            bag.remove(piece);
+                            match bag.is_empty() {
+                                true => EnumSet::all(),
+                                false => bag,
+                            }
+                        }),
+                };
+                variants
+                    .iter()
+                    .map(|&variant| GameState { bag, ..variant })
+                    .find(|state| self.mode.contains(depth, state))
+                    .map(|state| (depth, state))
+            });
+
+        let (depth, state) = match found {
+            Some(v) => v,
+            None => {
+                self.current = state;
+                self.queue = queue.iter().copied().collect();
+                self.mode = initial_mode(&self.options, state, queue);
+                return Some(Recovery::Rebuilt);
+            }
+        };
+        let recovery = match depth > 0 || state != self.current {
+            true => Some(Recovery::Rerooted),
+            false => None,
         };
+        self.progress.skip(depth as u32, &self.current, &state);
+        self.current = state;
+        self.queue.drain(..depth);
+        if let Some(to) = self.mode.reroot(&self.options, depth, state) {
+            self.switch(to);
+        }
+        for &piece in &queue[known.len() - depth..] {
+            self.new_piece(piece);
+        }
+        recovery
     }
 
     pub fn new_piece(&mut self, piece: Piece) {
@@ -79,42 +386,319 @@ impl Bot {
         self.mode.new_piece(&self.options, piece);
     }
 
+    /// Replaces the garbage waiting to be sent to the bot. Every position in the search depends
+    /// on the garbage queue, so a different queue starts a new search, while the same one is
+    /// ignored.
+    pub fn set_garbage(&mut self, garbage: GarbageQueue) {
+        puffin::profile_function!();
+        if self.current.garbage != garbage {
+            self.current.garbage = garbage;
+            let config = &self.options.config;
+            if config.sprint.enabled {
+                self.switch(ModeSwitch::Sprint);
+            } else if config.ultra.enabled {
+                self.switch(ModeSwitch::Ultra);
+            } else if Dig::eligible(&self.options, &self.current) {
+                self.switch(ModeSwitch::Dig);
+            } else {
+                self.switch(ModeSwitch::Freestyle);
+            }
+        }
+    }
+
     pub fn suggest(&self) -> Vec<Placement> {
         puffin::profile_function!();
         self.mode.suggest(&self.options)
     }
 
+    /// The `count` best moves the bot is considering, best first.
+    pub fn candidates(&self, count: usize) -> Vec<Candidate> {
+        puffin::profile_function!();
+        self.mode.candidates(&self.options, count)
+    }
+
+    /// The line the bot expects to be played, up to `depth` moves long.
+    pub fn plan(&self, depth: usize) -> Vec<PlannedMove> {
+        puffin::profile_function!();
+        self.mode.plan(&self.options, depth)
+    }
+
+    /// Exports the search tree up to `depth` moves deep, keeping the `breadth` best moves for
+    /// each possible next piece.
+    pub fn tree(&self, depth: usize, breadth: usize) -> TreeNode {
+        puffin::profile_function!();
+        self.mode.tree(&self.options, depth, breadth)
+    }
+
+    pub fn config(&self) -> &BotConfig {
+        &self.options.config
+    }
+
+    /// Chance of the move suggested by the perfect clear search leading to a perfect clear, if a
+    /// search has finished.
+    pub fn perfect_clear_probability(&self) -> Option<f64> {
+        self.mode.perfect_clear_probability()
+    }
+
+    /// Progress clearing garbage, if digging is enabled.
+    pub fn dig_progress(&self) -> Option<DigProgress> {
+        let config = &self.options.config.dig;
+        config.enabled.then(|| DigProgress {
+            garbage_remaining: self.current.garbage_rows as u32,
+            garbage_cleared: self.progress.garbage_cleared,
+            pieces: self.progress.pieces,
+            lines_remaining: config
+                .lines
+                .map(|lines| lines.saturating_sub(self.progress.garbage_cleared)),
+        })
+    }
+
+    /// Inputs to play `mv` from the current position, including a hold if needed.
+    pub fn path(&self, mv: Placement) -> Option<Path> {
+        puffin::profile_function!();
+        let piece = mv.location.piece;
+        let rules = &self.options.config.rules;
+        let mut path = Paths::new(&self.current.board, piece, rules).path(rules, mv)?;
+        let hold = rules.hold != HoldMode::Disabled
+            && self
+                .queue
+                .front()
+                .is_some_and(|&next| self.current.held_by(mv, next));
+        if hold {
+            path.inputs.insert(0, Input::Hold);
+            path.frames += rules.handling.frames(Input::Hold, 1);
+        }
+        Some(path)
+    }
+
     pub fn do_work(&self) -> Statistics {
         puffin::profile_function!();
-        self.mode.do_work(&self.options)
+        self.mode.do_work(&self.options, &self.progress)
+    }
+
+    /// Shrinks the search tree until it fits in `budget` bytes with some room to grow, pruning
+    /// the least promising moves if needed. Returns whether it fits.
+    pub fn fit_memory(&mut self, budget: usize) -> bool {
+        puffin::profile_function!();
+        if self.memory() <= budget {
+            return true;
+        }
+        // positions left behind by earlier moves are dropped without losing anything
+        self.mode.compact(&self.options, usize::MAX);
+        let mut keep = 16;
+        while self.mode.memory() > budget / 4 * 3 && keep > 0 {
+            self.mode.compact(&self.options, keep);
+            keep /= 2;
+        }
+        self.mode.memory() <= budget
+    }
+
+    /// Approximate number of bytes used by the search tree.
+    pub fn memory(&self) -> usize {
+        self.mode.memory()
+    }
+
+    /// Makes running calls to `do_work` return soon, so that the bot can be updated.
+    pub fn interrupt(&self) {
+        self.mode.interrupt();
+    }
+
+    /// Plays `mv` as if its piece was the next one, for when the queue the bot knows doesn't
+    /// allow it, and starts a new search from the resulting position.
+    fn restart(&mut self, mv: Placement) -> Recovery {
+        puffin::profile_function!();
+        // the frontend used up a piece, but which one it was is anyone's guess
+        self.queue.pop_front();
+        let frames = self.path(mv).map_or(0, |path| path.frames);
+        let info = self
+            .current
+            .advance(&self.options.config.rules, mv.location.piece, mv, false);
+        self.progress.record(&info, frames);
+        self.mode = initial_mode(&self.options, self.current, self.queue.make_contiguous());
+        Recovery::Rebuilt
     }
 
     fn switch(&mut self, to: ModeSwitch) {
         puffin::profile_function!();
-        match to {
-            ModeSwitch::Freestyle => {
-                self.mode =
-                    Freestyle::new(&self.options, self.current, self.queue.make_contiguous()).into()
+        let (options, root, queue) = (&self.options, self.current, self.queue.make_contiguous());
+        // the book and perfect clear modes search freestyle moves alongside, so that search
+        // carries over as long as it is still at the current position
+        let placeholder = DagMode::<Freestyle>::new(options, root, &[]).into();
+        let freestyle = match std::mem::replace(&mut self.mode, placeholder) {
+            ModeEnum::Freestyle(freestyle) => Some(freestyle),
+            ModeEnum::PerfectClear(pc) => Some(pc.into_freestyle()),
+            ModeEnum::Book(book) => Some(book.into_freestyle()),
+            _ => None,
+        }
+        .filter(|freestyle| *freestyle.root() == root)
+        .unwrap_or_else(|| DagMode::new(options, root, queue));
+        self.mode = match to {
+            ModeSwitch::Dig => DagMode::<Dig>::new(options, root, queue).into(),
+            ModeSwitch::Freestyle => freestyle.into(),
+            ModeSwitch::PerfectClear => PerfectClear::new(freestyle, root, queue).into(),
+            ModeSwitch::Sprint => DagMode::<Sprint>::new(options, root, queue).into(),
+            ModeSwitch::Ultra => DagMode::<Ultra>::new(options, root, queue).into(),
+        };
+    }
+}
+
+impl Progress {
+    fn record(&mut self, info: &PlacementInfo, frames: u32) {
+        self.pieces += 1;
+        self.lines_cleared += info.lines_cleared;
+        self.garbage_cleared += info.garbage_cleared;
+        self.frames += frames;
+    }
+
+    /// Counts `depth` moves that were played without the bot seeing them, going from `from` to
+    /// `to`. Lines are worked out from the cells on the board, so garbage received in between
+    /// hides some of them, and frames are taken to be the average so far.
+    fn skip(&mut self, depth: u32, from: &GameState, to: &GameState) {
+        let cells =
+            |state: &GameState| -> u32 { state.board.cols().iter().map(|c| c.count_ones()).sum() };
+        let width = from.board.cols().len() as u32;
+        self.lines_cleared += (cells(from) + 4 * depth).saturating_sub(cells(to)) / width;
+        self.frames += depth * self.frames.checked_div(self.pieces).unwrap_or(0);
+        self.pieces += depth;
+    }
+}
+
+fn initial_mode(options: &BotOptions, root: GameState, queue: &[Piece]) -> ModeEnum {
+    if options.config.sprint.enabled {
+        DagMode::<Sprint>::new(options, root, queue).into()
+    } else if options.config.ultra.enabled {
+        DagMode::<Ultra>::new(options, root, queue).into()
+    } else if let Some(book) = Book::new(options, root, queue) {
+        book.into()
+    } else if Dig::eligible(options, &root) {
+        DagMode::<Dig>::new(options, root, queue).into()
+    } else if PerfectClear::eligible(options, &root) {
+        PerfectClear::new(DagMode::new(options, root, queue), root, queue).into()
+    } else {
+        DagMode::<Freestyle>::new(options, root, queue).into()
+    }
+}
+
+/// Selects a leaf of `dag` and expands it, evaluating each child with `evaluate`.
+fn expand<E: Evaluation>(
+    dag: &Dag<E>,
+    options: &BotOptions,
+    exploitation: f64,
+    evaluate: impl Fn(GameState, &PlacementInfo, MoveCost) -> (E, E::Reward),
+) -> Statistics {
+    let mut new_stats = Statistics::default();
+    new_stats.selections += 1;
+
+    if let Some(node) = dag.select(
+        &options.config.rules,
+        options.speculate,
+        options.config.selection,
+        exploitation,
+    ) {
+        let (state, next) = node.state();
+        let next_possibilities = next.map(EnumSet::only).unwrap_or(state.bag);
+        let can_hold = state.can_hold(&options.config.rules);
+
+        let mut moves = EnumMap::default();
+        {
+            puffin::profile_scope!("movegen");
+            let reserve = match can_hold {
+                true => EnumSet::only(state.reserve),
+                false => EnumSet::empty(),
+            };
+            for piece in next_possibilities | reserve {
+                moves[piece] = find_moves(&state.board, piece, &options.config.rules);
+            }
+        }
+
+        let mut children: EnumMap<_, Vec<_>> = EnumMap::default();
+
+        {
+            puffin::profile_scope!("eval");
+            for next in next_possibilities {
+                let moves = moves[next]
+                    .iter()
+                    .chain(if next == state.reserve || !can_hold {
+                        [].iter()
+                    } else {
+                        moves[state.reserve].iter()
+                    });
+                for &(mv, mut cost) in moves {
+                    let held = state.held_by(mv, next);
+                    if held {
+                        cost.frames += options.config.rules.handling.frames(Input::Hold, 1);
+                    }
+
+                    let mut state = state;
+                    let info = state.advance(&options.config.rules, next, mv, held);
+
+                    let (eval, reward) = evaluate(state, &info, cost);
+
+                    children[next].push(ChildData {
+                        resulting_state: state,
+                        mv,
+                        eval,
+                        reward,
+                    });
+                }
+
+                new_stats.nodes += children[next].len() as u64;
             }
         }
+
+        new_stats.expansions += 1;
+        node.expand(children);
+    }
+    new_stats.memory = dag.memory() as u64;
+
+    new_stats
+}
+
+impl TreeNode {
+    /// Renders the tree in the Graphviz DOT format.
+    pub fn to_dot(&self) -> String {
+        let mut dot = String::from("digraph {\n");
+        let mut next_id = 0;
+        self.write_dot(&mut dot, &mut next_id);
+        dot.push_str("}\n");
+        dot
+    }
+
+    fn write_dot(&self, dot: &mut String, next_id: &mut usize) -> usize {
+        let id = *next_id;
+        *next_id += 1;
+        dot.push_str(&format!(
+            "  n{} [label=\"{:.2}\\n{} visits\"];\n",
+            id, self.eval, self.visits
+        ));
+        for edge in &self.children {
+            let child = edge.node.write_dot(dot, next_id);
+            let location = edge.mv.location;
+            dot.push_str(&format!(
+                "  n{} -> n{} [label=\"{:?}{}: {:?} {:?} {},{}\\n{:.2}\"{}];\n",
+                id,
+                child,
+                edge.piece,
+                if edge.speculated { "?" } else { "" },
+                location.piece,
+                location.rotation,
+                location.x,
+                location.y,
+                edge.eval,
+                if edge.speculated { " style=dashed" } else { "" },
+            ));
+        }
+        id
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, Default)]
 pub struct Statistics {
     pub nodes: u64,
     pub selections: u64,
     pub expansions: u64,
-}
-
-impl Default for Statistics {
-    fn default() -> Self {
-        Statistics {
-            nodes: 0,
-            selections: 0,
-            expansions: 0,
-        }
-    }
+    /// Bytes used by the search tree when the statistics were taken.
+    pub memory: u64,
 }
 
 impl Statistics {
@@ -122,5 +706,146 @@ impl Statistics {
         self.nodes += other.nodes;
         self.selections += other.selections;
         self.expansions += other.expansions;
+        self.memory = other.memory;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_state(reserve: Piece, hold_empty: bool) -> GameState {
+        GameState {
+            board: Board::new(10),
+            bag: EnumSet::all(),
+            reserve,
+            back_to_back: 0,
+            combo: 0,
+            garbage: GarbageQueue::default(),
+            hold_locked: false,
+            hold_empty,
+            garbage_rows: 0,
+        }
+    }
+
+    fn new_bot(config: BotConfig, root: GameState, queue: &[Piece]) -> Bot {
+        let options = BotOptions {
+            speculate: false,
+            config: Arc::new(config),
+        };
+        Bot::new(options, root, queue)
+    }
+
+    #[test]
+    fn restricted_hold_with_empty_hold_slot() {
+        let mut config = BotConfig::default();
+        config.rules.hold = HoldMode::Restricted;
+        let queue = [Piece::I, Piece::O, Piece::L];
+        let mut bot = new_bot(config, empty_state(Piece::T, true), &queue);
+        for _ in 0..50 {
+            bot.do_work();
+        }
+        let rules = bot.options.config.rules.clone();
+
+        // playing the piece in play leaves the hold slot empty and unlocked
+        let (mv, _) = find_moves(&bot.current.board, Piece::T, &rules)[0];
+        assert!(!bot.current.held_by(mv, Piece::I));
+        let mut kept = bot.current;
+        kept.advance(&rules, Piece::I, mv, false);
+        assert!(kept.hold_empty && kept.can_hold(&rules));
+        assert!(bot.mode.contains(1, &kept));
+
+        // holding it brings out the next piece, which locks hold for the placement after
+        let (mv, _) = find_moves(&bot.current.board, Piece::I, &rules)[0];
+        assert!(bot.current.held_by(mv, Piece::I));
+        let mut held = bot.current;
+        held.advance(&rules, Piece::I, mv, true);
+        assert!(!held.hold_empty && !held.can_hold(&rules));
+        assert!(bot.mode.contains(1, &held));
+
+        assert_eq!(bot.advance(mv), None);
+        assert_eq!(bot.current, held);
+        for _ in 0..50 {
+            bot.do_work();
+        }
+        assert_eq!(bot.suggest()[0].location.piece, Piece::O);
+    }
+
+    #[test]
+    fn resync_to_the_current_position() {
+        let mut root = empty_state(Piece::T, false);
+        root.board.insert_garbage(2, Some(0));
+        root.garbage_rows = 2;
+        let queue = [Piece::I, Piece::O, Piece::L];
+        let mut bot = new_bot(BotConfig::default(), root, &queue);
+        bot.do_work();
+
+        assert_eq!(bot.resync(root, &queue), None);
+        // a frontend that doesn't mark garbage rows still matches
+        let unmarked = GameState {
+            garbage_rows: 0,
+            ..root
+        };
+        assert_eq!(bot.resync(unmarked, &queue), None);
+        assert_eq!(bot.current, root);
+    }
+
+    #[test]
+    fn resync_to_a_position_the_search_reached() {
+        let mut config = BotConfig::default();
+        config.rules.hold = HoldMode::Restricted;
+        let queue = [Piece::I, Piece::O, Piece::L];
+        let mut bot = new_bot(config, empty_state(Piece::T, false), &queue);
+        for _ in 0..50 {
+            bot.do_work();
+        }
+        let rules = bot.options.config.rules.clone();
+
+        // holding locks hold, which the frontend can't say
+        let (mv, _) = find_moves(&bot.current.board, Piece::T, &rules)[0];
+        let mut held = bot.current;
+        held.advance(&rules, Piece::I, mv, true);
+        assert!(held.hold_locked);
+        let sent = GameState {
+            hold_locked: false,
+            ..held
+        };
+        assert_eq!(bot.resync(sent, &queue[1..]), Some(Recovery::Rerooted));
+        assert_eq!(bot.current, held);
+        assert!(bot.queue.iter().eq(&queue[1..]));
+    }
+
+    #[test]
+    fn resync_to_an_unknown_position() {
+        let queue = [Piece::I, Piece::O, Piece::L];
+        let mut bot = new_bot(BotConfig::default(), empty_state(Piece::T, false), &queue);
+        bot.do_work();
+
+        let mut elsewhere = empty_state(Piece::S, false);
+        elsewhere.board.insert_garbage(1, Some(3));
+        assert_eq!(bot.resync(elsewhere, &queue), Some(Recovery::Rebuilt));
+        assert_eq!(bot.current, elsewhere);
+    }
+
+    #[test]
+    fn unknown_next_piece_rebuilds() {
+        let queue = [Piece::I, Piece::O, Piece::L];
+        let root = empty_state(Piece::T, false);
+        let mut bot = new_bot(BotConfig::default(), root, &queue);
+        // a search that lost track of the queue
+        bot.mode = DagMode::<Freestyle>::new(&bot.options, root, &[]).into();
+
+        let rules = bot.options.config.rules.clone();
+        let (mv, _) = find_moves(&root.board, Piece::I, &rules)[0];
+        assert_eq!(bot.advance(mv), Some(Recovery::Rebuilt));
+        let mut expected = root;
+        expected.advance(&rules, Piece::I, mv, false);
+        assert_eq!(bot.current, expected);
+        assert!(bot.queue.iter().eq(&queue[1..]));
+
+        // the new search knows the queue again
+        bot.do_work();
+        let (mv, _) = find_moves(&bot.current.board, Piece::O, &rules)[0];
+        assert_eq!(bot.advance(mv), None);
     }
 }
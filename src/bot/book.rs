@@ -0,0 +1,632 @@
+use ahash::AHashSet;
+use enumset::EnumSet;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use super::dag_mode::DagMode;
+use super::freestyle::Freestyle;
+use super::{BotOptions, Candidate, Mode, ModeSwitch, PlannedMove, Progress, Statistics, TreeNode};
+use crate::dag::UnknownPiece;
+use crate::data::*;
+use crate::movegen::find_moves;
+use crate::rules::GameRules;
+
+/// Builds a known opener while the queue allows it, then hands off to freestyle.
+pub struct Book {
+    freestyle: DagMode<Freestyle>,
+    root: GameState,
+    queue: Vec<Piece>,
+    /// Index of the opener being built.
+    opener: usize,
+    next_move: Placement,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    /// Openers to try, in order of preference. Defaults to those in `openers.json`.
+    pub openers: Vec<Opener>,
+}
+
+/// A shape to build on the board, and the bags it can be built from. Pieces can be placed in any
+/// order that the queue allows, and the shape can take more than one bag.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "RawOpener", into = "RawOpener")]
+pub struct Opener {
+    pub name: String,
+    width: usize,
+    pieces: Vec<PieceLocation>,
+    /// Pairs of pieces where the first has to be drawn before the second in the same bag.
+    order: Vec<(Piece, Piece)>,
+}
+
+/// An opener as written in the data file, with rows listed from top to bottom. Each cell is
+/// either `.` or the letter of the piece occupying it. Each entry of `order` is written like
+/// `L<J`, for an L that has to come before the J of its bag.
+#[derive(Serialize, Deserialize)]
+struct RawOpener {
+    name: String,
+    board: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    order: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        static OPENERS: Lazy<Vec<Opener>> =
+            Lazy::new(|| serde_json::from_str(include_str!("../openers.json")).unwrap());
+        Config {
+            enabled: false,
+            openers: OPENERS.clone(),
+        }
+    }
+}
+
+impl Book {
+    /// Starts building the first opener the board and queue allow, if there is one.
+    pub fn new(options: &BotOptions, root: GameState, queue: &[Piece]) -> Option<Self> {
+        let config = &options.config.book;
+        if !config.enabled {
+            return None;
+        }
+        config.openers.iter().enumerate().find_map(|(i, opener)| {
+            let next_move = opener.next_move(options, &root, queue)?;
+            Some(Book {
+                freestyle: DagMode::new(options, root, queue),
+                root,
+                queue: queue.to_vec(),
+                opener: i,
+                next_move,
+            })
+        })
+    }
+
+    /// Finds the next move of the current opener, or another opener if it can't be finished.
+    fn replan(&mut self, options: &BotOptions) -> bool {
+        let openers = &options.config.book.openers;
+        let current = self.opener;
+        let order = std::iter::once(current).chain((0..openers.len()).filter(|&i| i != current));
+        for i in order {
+            if let Some(mv) = openers[i].next_move(options, &self.root, &self.queue) {
+                self.opener = i;
+                self.next_move = mv;
+                return true;
+            }
+        }
+        false
+    }
+
+    /// The freestyle search, which has been following the opener.
+    pub fn into_freestyle(self) -> DagMode<Freestyle> {
+        self.freestyle
+    }
+}
+
+impl Mode for Book {
+    fn advance(
+        &mut self,
+        options: &BotOptions,
+        mv: Placement,
+        held: bool,
+    ) -> Result<Option<ModeSwitch>, UnknownPiece> {
+        puffin::profile_function!();
+        // the freestyle search is told the same pieces, so it fails first if the queue is empty
+        self.freestyle.advance(options, mv, held)?;
+        self.root
+            .advance(&options.config.rules, self.queue.remove(0), mv, held);
+        match self.replan(options) {
+            true => Ok(None),
+            false => Ok(Some(ModeSwitch::Freestyle)),
+        }
+    }
+
+    fn new_piece(&mut self, options: &BotOptions, piece: Piece) {
+        puffin::profile_function!();
+        self.queue.push(piece);
+        self.freestyle.new_piece(options, piece);
+        // more pieces can only keep the current opener possible
+        self.replan(options);
+    }
+
+    fn suggest(&self, _options: &BotOptions) -> Vec<Placement> {
+        vec![self.next_move]
+    }
+
+    fn candidates(&self, options: &BotOptions, count: usize) -> Vec<Candidate> {
+        self.freestyle.candidates(options, count)
+    }
+
+    fn plan(&self, options: &BotOptions, depth: usize) -> Vec<PlannedMove> {
+        self.freestyle.plan(options, depth)
+    }
+
+    fn tree(&self, options: &BotOptions, depth: usize, breadth: usize) -> TreeNode {
+        self.freestyle.tree(options, depth, breadth)
+    }
+
+    fn is_expected(&self, mv: Placement) -> bool {
+        self.freestyle.is_expected(mv)
+    }
+
+    fn contains(&self, depth: usize, state: &GameState) -> bool {
+        self.freestyle.contains(depth, state)
+    }
+
+    fn reroot(
+        &mut self,
+        options: &BotOptions,
+        depth: usize,
+        state: GameState,
+    ) -> Option<ModeSwitch> {
+        puffin::profile_function!();
+        self.root = state;
+        self.queue.drain(..depth);
+        self.freestyle.reroot(options, depth, state);
+        match self.replan(options) {
+            true => None,
+            false => Some(ModeSwitch::Freestyle),
+        }
+    }
+
+    fn memory(&self) -> usize {
+        self.freestyle.memory()
+    }
+
+    fn compact(&mut self, options: &BotOptions, keep: usize) {
+        self.freestyle.compact(options, keep)
+    }
+
+    fn do_work(&self, options: &BotOptions, progress: &Progress) -> Statistics {
+        // the freestyle search carries on once the opener is done
+        self.freestyle.do_work(options, progress)
+    }
+}
+
+impl Opener {
+    /// First move of a way to finish the opener from `state`, if the board matches the opener and
+    /// the queue and bag allow finishing it.
+    fn next_move(
+        &self,
+        options: &BotOptions,
+        state: &GameState,
+        queue: &[Piece],
+    ) -> Option<Placement> {
+        if state.board.width() as usize != self.width {
+            return None;
+        }
+        let mut shape = Board::new(self.width as u8);
+        let mut remaining = vec![];
+        for &piece in &self.pieces {
+            shape.place(piece);
+            if piece.cells().iter().all(|&c| !state.board.occupied(c)) {
+                remaining.push(piece);
+            } else if !piece.cells().iter().all(|&c| state.board.occupied(c)) {
+                return None;
+            }
+        }
+        let matches = state
+            .board
+            .cols()
+            .iter()
+            .zip(shape.cols())
+            .all(|(&c, &s)| c & !s == 0);
+        if !matches
+            || remaining.is_empty()
+            || !self.order_allowed(options, state, queue, &remaining)
+        {
+            return None;
+        }
+
+        let mut planner = Planner {
+            rules: &options.config.rules,
+            speculate: options.speculate,
+            queue,
+            remaining,
+            dead_ends: AHashSet::new(),
+        };
+        planner.plan(state, 0)
+    }
+
+    /// Whether the order rules hold for the `remaining` pieces of the opener that are still to be
+    /// drawn from the current bag. The reserve piece can be played at any time, so it counts as
+    /// coming first, and pieces that aren't in the queue yet could still come in the right order.
+    fn order_allowed(
+        &self,
+        options: &BotOptions,
+        state: &GameState,
+        queue: &[Piece],
+        remaining: &[PieceLocation],
+    ) -> bool {
+        let bag = &queue[..queue.len().min(state.bag.len())];
+        let position = |piece: Piece| match piece == state.reserve {
+            true if state.can_hold(&options.config.rules) => Some(0),
+            _ => bag.iter().position(|&p| p == piece).map(|i| i + 1),
+        };
+        let remaining: EnumSet<Piece> = remaining.iter().map(|p| p.piece).collect();
+        self.order.iter().all(|&(first, second)| {
+            if !remaining.contains(first) || !remaining.contains(second) {
+                return true;
+            }
+            match (position(first), position(second)) {
+                (_, None) => true,
+                (Some(a), Some(b)) => a < b,
+                (None, Some(_)) => false,
+            }
+        })
+    }
+}
+
+struct Planner<'a> {
+    rules: &'a GameRules,
+    speculate: bool,
+    queue: &'a [Piece],
+    remaining: Vec<PieceLocation>,
+    /// Positions the opener can't be finished from. The remaining pieces are implied by the
+    /// board, so they don't need to be part of the key.
+    dead_ends: AHashSet<(Board, Piece, bool, bool, usize)>,
+}
+
+impl Planner<'_> {
+    /// Depth-first search for an order to place the remaining pieces in.
+    fn plan(&mut self, state: &GameState, depth: usize) -> Option<Placement> {
+        let next = match self.queue.get(depth) {
+            Some(&next) => next,
+            None => {
+                // we can't know the order of unseen pieces, so settle for them being available
+                let bag = match self.speculate {
+                    true => state.bag,
+                    false => EnumSet::all(),
+                };
+                let available = bag | state.reserve;
+                let needed: EnumSet<_> = self.remaining.iter().map(|p| p.piece).collect();
+                // openers spanning several bags get their other pieces from the next bag, so only
+                // the piece waiting in hold can be left out of this one
+                let possible = match self.remaining.len() > bag.len() {
+                    true => bag.difference(needed).len() <= 1,
+                    false => available.is_superset(needed),
+                };
+                return match possible && depth > 0 {
+                    true => Some(Placement {
+                        location: self.remaining[0],
+                        spin: Spin::None,
+                    }),
+                    false => None,
+                };
+            }
+        };
+
+        let key = (
+            state.board,
+            state.reserve,
+            state.hold_locked,
+            state.hold_empty,
+            depth,
+        );
+        if self.dead_ends.contains(&key) {
+            return None;
+        }
+
+        let mut pieces = EnumSet::only(next);
+        if state.can_hold(self.rules) {
+            pieces |= state.reserve;
+        }
+        for piece in pieces {
+            let moves = find_moves(&state.board, piece, self.rules);
+            for i in 0..self.remaining.len() {
+                let target = self.remaining[i];
+                if target.piece != piece {
+                    continue;
+                }
+                let canonical = target.canonical_form();
+                let mv = match moves
+                    .iter()
+                    .find(|(mv, _)| mv.location.canonical_form() == canonical)
+                {
+                    Some(&(mv, _)) => mv,
+                    None => continue,
+                };
+
+                let mut child = *state;
+                child.advance(self.rules, next, mv, state.held_by(mv, next));
+                self.remaining.swap_remove(i);
+                let done = self.remaining.is_empty() || self.plan(&child, depth + 1).is_some();
+                self.remaining.push(target);
+                let last = self.remaining.len() - 1;
+                self.remaining.swap(i, last);
+                if done {
+                    return Some(mv);
+                }
+            }
+        }
+        self.dead_ends.insert(key);
+        None
+    }
+}
+
+impl TryFrom<RawOpener> for Opener {
+    type Error = String;
+
+    fn try_from(raw: RawOpener) -> Result<Self, String> {
+        let width = raw.board.iter().map(|row| row.len()).max().unwrap_or(0);
+        if !(4..=MAX_WIDTH).contains(&width) {
+            return Err(format!("opener {} has an unsupported width", raw.name));
+        }
+        let mut cells = vec![];
+        for (y, row) in raw.board.iter().rev().enumerate() {
+            for (x, c) in row.chars().enumerate() {
+                if c != '.' {
+                    cells.push(((x as i8, y as i8), c));
+                }
+            }
+        }
+
+        let mut pieces = vec![];
+        while let Some(&(start, letter)) = cells.first() {
+            // flood fill the cells of one piece
+            let mut group = vec![start];
+            let mut i = 0;
+            while i < group.len() {
+                let (x, y) = group[i];
+                for n in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+                    if !group.contains(&n) && cells.contains(&(n, letter)) {
+                        group.push(n);
+                    }
+                }
+                i += 1;
+            }
+            cells.retain(|(c, _)| !group.contains(c));
+
+            let piece = piece_from_letter(letter)
+                .ok_or_else(|| format!("opener {} has unknown piece {}", raw.name, letter))?;
+            let location = locate(piece, &group)
+                .ok_or_else(|| format!("opener {} has a misshapen {} piece", raw.name, letter))?;
+            pieces.push(location);
+        }
+
+        let mut order = vec![];
+        for rule in &raw.order {
+            let letters: Vec<char> = rule.chars().filter(|c| !c.is_whitespace()).collect();
+            let pair = match letters[..] {
+                [first, '<', second] => piece_from_letter(first).zip(piece_from_letter(second)),
+                _ => None,
+            };
+            let pair =
+                pair.ok_or_else(|| format!("opener {} has a malformed order {}", raw.name, rule))?;
+            order.push(pair);
+        }
+
+        Ok(Opener {
+            name: raw.name,
+            width,
+            pieces,
+            order,
+        })
+    }
+}
+
+impl From<Opener> for RawOpener {
+    fn from(opener: Opener) -> Self {
+        let height = opener
+            .pieces
+            .iter()
+            .flat_map(|p| p.cells())
+            .map(|(_, y)| y + 1)
+            .max()
+            .unwrap_or(0);
+        let mut rows = vec![vec!['.'; opener.width]; height as usize];
+        for piece in &opener.pieces {
+            for (x, y) in piece.cells() {
+                rows[y as usize][x as usize] = letter_of(piece.piece);
+            }
+        }
+        RawOpener {
+            name: opener.name,
+            board: rows.iter().rev().map(|row| row.iter().collect()).collect(),
+            order: opener
+                .order
+                .iter()
+                .map(|&(first, second)| format!("{}<{}", letter_of(first), letter_of(second)))
+                .collect(),
+        }
+    }
+}
+
+fn piece_from_letter(c: char) -> Option<Piece> {
+    EnumSet::<Piece>::all()
+        .iter()
+        .find(|&p| letter_of(p) == c.to_ascii_uppercase())
+}
+
+fn letter_of(piece: Piece) -> char {
+    match piece {
+        Piece::I => 'I',
+        Piece::O => 'O',
+        Piece::T => 'T',
+        Piece::L => 'L',
+        Piece::J => 'J',
+        Piece::S => 'S',
+        Piece::Z => 'Z',
+    }
+}
+
+/// The location of `piece` that covers exactly `cells`.
+fn locate(piece: Piece, cells: &[(i8, i8)]) -> Option<PieceLocation> {
+    if cells.len() != 4 {
+        return None;
+    }
+    let (x, y) = cells[0];
+    for rotation in [
+        Rotation::North,
+        Rotation::East,
+        Rotation::South,
+        Rotation::West,
+    ] {
+        for (dx, dy) in rotation.rotate_cells(piece.cells()) {
+            let location = PieceLocation {
+                piece,
+                rotation,
+                x: x - dx,
+                y: y - dy,
+            };
+            if location.cells().iter().all(|c| cells.contains(c)) {
+                return Some(location);
+            }
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::bot::{initial_mode, BotConfig, ModeEnum};
+
+    fn options(speculate: bool) -> BotOptions {
+        let mut config = BotConfig::default();
+        config.book.enabled = true;
+        BotOptions {
+            speculate,
+            config: Arc::new(config),
+        }
+    }
+
+    fn empty_state(reserve: Piece, bag: EnumSet<Piece>) -> GameState {
+        GameState {
+            board: Board::new(10),
+            bag,
+            reserve,
+            back_to_back: 0,
+            combo: 0,
+            garbage: GarbageQueue::default(),
+            hold_locked: false,
+            hold_empty: false,
+            garbage_rows: 0,
+        }
+    }
+
+    fn shape(opener: &Opener) -> Board {
+        let mut shape = Board::new(opener.width as u8);
+        for &piece in &opener.pieces {
+            shape.place(piece);
+        }
+        shape
+    }
+
+    fn bag(order: &str) -> Vec<Piece> {
+        order
+            .chars()
+            .map(|c| piece_from_letter(c).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn default_openers_can_be_built() {
+        let options = options(false);
+        let rules = &options.config.rules;
+        for opener in &options.config.book.openers {
+            let mut pieces = opener.pieces.clone();
+            pieces.sort_by_key(|p| p.cells().iter().map(|&(_, y)| y).min());
+            let mut queue: Vec<_> = pieces.iter().map(|p| p.piece).collect();
+
+            let mut state = empty_state(Piece::T, EnumSet::all());
+            while !queue.is_empty() {
+                let mv = opener
+                    .next_move(&options, &state, &queue)
+                    .unwrap_or_else(|| panic!("opener {} can't be finished", opener.name));
+                let next = queue.remove(0);
+                let held = state.held_by(mv, next);
+                state.advance(rules, next, mv, held);
+            }
+            assert_eq!(
+                state.board,
+                shape(opener),
+                "opener {} built wrong",
+                opener.name
+            );
+        }
+    }
+
+    #[test]
+    fn follows_an_opener_through_the_bag() {
+        let options = options(true);
+        let rules = &options.config.rules;
+        let orders = [
+            "ISZLJOT", "TIOSJZL", "OILJTSZ", "LJIOSTZ", "ZISOTLJ", "JLTOIZS",
+        ];
+        for order in orders {
+            // the first piece is in hold and the rest of the bag is in the queue
+            let mut pieces = bag(order);
+            pieces.extend(bag("TIOLJSZ"));
+            pieces.extend(bag("SZLJOIT"));
+            let reserve = pieces.remove(0);
+            let root = empty_state(reserve, EnumSet::all() - reserve);
+            let mut upcoming = pieces.split_off(6);
+
+            let mut book = Book::new(&options, root, &pieces)
+                .unwrap_or_else(|| panic!("no opener for {order}"));
+            let opener = book.opener;
+            let mut state = root;
+            loop {
+                let mv = book.suggest(&options)[0];
+                let next = book.queue[0];
+                let held = state.held_by(mv, next);
+                state.advance(rules, next, mv, held);
+                let switch = book.advance(&options, mv, held).unwrap();
+                if !upcoming.is_empty() {
+                    book.new_piece(&options, upcoming.remove(0));
+                }
+                if switch.is_some() {
+                    break;
+                }
+                assert_eq!(book.opener, opener, "changed opener for {order}");
+            }
+            let opener = &options.config.book.openers[opener];
+            assert_eq!(
+                state.board,
+                shape(opener),
+                "{} built wrong for {order}",
+                opener.name
+            );
+        }
+    }
+
+    #[test]
+    fn falls_back_to_freestyle_without_an_opener() {
+        let options = options(false);
+        let rules = &options.config.rules;
+        let queue = bag("ISZLJOTIOS");
+
+        // no opener has anything this high up
+        let mut root = empty_state(Piece::T, EnumSet::all());
+        root.board.cols_mut()[0] = 1 << 5;
+        assert!(Book::new(&options, root, &queue).is_none());
+        assert!(!matches!(
+            initial_mode(&options, root, &queue),
+            ModeEnum::Book(_)
+        ));
+
+        // a move off every opener's shape leaves the book
+        let root = empty_state(Piece::T, EnumSet::all());
+        let mut book = Book::new(&options, root, &queue).unwrap();
+        let openers = &options.config.book.openers;
+        let (mv, after) = find_moves(&root.board, queue[0], rules)
+            .into_iter()
+            .map(|(mv, _)| {
+                let mut after = root;
+                after.advance(rules, queue[0], mv, false);
+                (mv, after)
+            })
+            .find(|(_, after)| {
+                openers
+                    .iter()
+                    .all(|o| o.next_move(&options, after, &queue[1..]).is_none())
+            })
+            .unwrap();
+        let switch = book.advance(&options, mv, false).unwrap();
+        assert!(matches!(switch, Some(ModeSwitch::Freestyle)));
+        assert_eq!(book.root, after);
+    }
+}
@@ -0,0 +1,218 @@
+use std::marker::PhantomData;
+use std::ops::Add;
+
+use ordered_float::OrderedFloat;
+
+use super::{
+    expand, BotOptions, Candidate, Mode, ModeSwitch, PlannedMove, Progress, Statistics, TreeEdge,
+    TreeNode,
+};
+use crate::dag::{self, Dag, Evaluation, UnknownPiece};
+use crate::data::*;
+use crate::movegen::MoveCost;
+
+/// A mode that searches with a [`Dag`], scoring positions with `V`.
+pub struct DagMode<V> {
+    dag: Dag<Eval>,
+    evaluator: PhantomData<V>,
+}
+
+/// What a [`DagMode`] plays for.
+pub(super) trait Evaluator {
+    /// Scores the position `state` reached by a placement, given how far the bot has come.
+    fn evaluate(
+        options: &BotOptions,
+        progress: &Progress,
+        state: GameState,
+        info: &PlacementInfo,
+        cost: MoveCost,
+    ) -> (Eval, Reward);
+
+    /// The mode better suited to `root`, if there is one.
+    fn switch(options: &BotOptions, root: &GameState) -> Option<ModeSwitch>;
+}
+
+impl<V> DagMode<V> {
+    pub fn new(_options: &BotOptions, root: GameState, queue: &[Piece]) -> Self {
+        DagMode {
+            dag: Dag::new(root, queue),
+            evaluator: PhantomData,
+        }
+    }
+
+    pub fn root(&self) -> &GameState {
+        self.dag.root()
+    }
+}
+
+impl<V: Evaluator> Mode for DagMode<V> {
+    fn advance(
+        &mut self,
+        options: &BotOptions,
+        mv: Placement,
+        held: bool,
+    ) -> Result<Option<ModeSwitch>, UnknownPiece> {
+        puffin::profile_function!();
+        self.dag.advance(&options.config.rules, mv, held)?;
+        Ok(V::switch(options, self.dag.root()))
+    }
+
+    fn new_piece(&mut self, _options: &BotOptions, piece: Piece) {
+        puffin::profile_function!();
+        self.dag.add_piece(piece);
+    }
+
+    fn suggest(&self, _options: &BotOptions) -> Vec<Placement> {
+        puffin::profile_function!();
+        self.dag.suggest()
+    }
+
+    fn candidates(&self, options: &BotOptions, count: usize) -> Vec<Candidate> {
+        puffin::profile_function!();
+        self.dag
+            .candidates(&options.config.rules, count)
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    }
+
+    fn plan(&self, options: &BotOptions, depth: usize) -> Vec<PlannedMove> {
+        puffin::profile_function!();
+        self.dag
+            .plan(&options.config.rules, depth)
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    }
+
+    fn tree(&self, options: &BotOptions, depth: usize, breadth: usize) -> TreeNode {
+        puffin::profile_function!();
+        self.dag.tree(&options.config.rules, depth, breadth).into()
+    }
+
+    fn is_expected(&self, mv: Placement) -> bool {
+        self.dag.is_expected(mv)
+    }
+
+    fn contains(&self, depth: usize, state: &GameState) -> bool {
+        self.dag.contains(depth, state)
+    }
+
+    fn reroot(
+        &mut self,
+        options: &BotOptions,
+        depth: usize,
+        state: GameState,
+    ) -> Option<ModeSwitch> {
+        puffin::profile_function!();
+        self.dag.reroot(depth, state);
+        V::switch(options, self.dag.root())
+    }
+
+    fn memory(&self) -> usize {
+        self.dag.memory()
+    }
+
+    fn compact(&mut self, options: &BotOptions, keep: usize) {
+        puffin::profile_function!();
+        self.dag.compact(&options.config.rules, keep);
+    }
+
+    fn do_work(&self, options: &BotOptions, progress: &Progress) -> Statistics {
+        puffin::profile_function!();
+        expand(
+            &self.dag,
+            options,
+            options.config.freestyle_exploitation,
+            |state, info, cost| V::evaluate(options, progress, state, info, cost),
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct Eval {
+    pub value: OrderedFloat<f32>,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub(super) struct Reward {
+    pub value: OrderedFloat<f32>,
+}
+
+impl From<dag::Candidate<Eval>> for Candidate {
+    fn from(c: dag::Candidate<Eval>) -> Self {
+        Candidate {
+            mv: c.mv,
+            eval: c.eval.value.0 as f64,
+            visits: c.visits,
+            expansions: c.expansions,
+            pv: c.pv,
+        }
+    }
+}
+
+impl From<dag::PlannedMove<Eval>> for PlannedMove {
+    fn from(m: dag::PlannedMove<Eval>) -> Self {
+        PlannedMove {
+            piece: m.piece,
+            speculated: m.speculated,
+            mv: m.mv,
+            eval: m.eval.value.0 as f64,
+            board: m.state.board,
+        }
+    }
+}
+
+impl From<dag::TreeNode<Eval>> for TreeNode {
+    fn from(node: dag::TreeNode<Eval>) -> Self {
+        TreeNode {
+            board: node.state.board,
+            eval: node.eval.value.0 as f64,
+            visits: node.visits,
+            expansions: node.expansions,
+            children: node
+                .children
+                .into_iter()
+                .map(|edge| TreeEdge {
+                    piece: edge.piece,
+                    speculated: edge.speculated,
+                    mv: edge.mv,
+                    eval: edge.eval.value.0 as f64,
+                    visits: edge.visits,
+                    node: edge.node.into(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl Evaluation for Eval {
+    type Reward = Reward;
+
+    fn average(of: impl Iterator<Item = Option<Self>>) -> Self {
+        let mut count = 0;
+        let sum: f32 = of
+            .map(|v| {
+                count += 1;
+                v.map(|e| e.value.0).unwrap_or(-1000.0)
+            })
+            .sum();
+        Eval {
+            value: (sum / count as f32).into(),
+        }
+    }
+
+    fn value(self) -> f64 {
+        self.value.0 as f64
+    }
+}
+
+impl Add<Reward> for Eval {
+    type Output = Self;
+
+    fn add(self, rhs: Reward) -> Eval {
+        Eval {
+            value: self.value + rhs.value,
+        }
+    }
+}
@@ -0,0 +1,199 @@
+use serde::{Deserialize, Serialize};
+
+use super::dag_mode::{Eval, Evaluator, Reward};
+use super::features::*;
+use super::{BotOptions, ModeSwitch, Progress};
+use crate::data::*;
+use crate::movegen::MoveCost;
+
+/// Clears the garbage at the bottom of the board in as few pieces as possible.
+pub struct Dig;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    /// Number of garbage lines to clear, for reporting how many are left.
+    pub lines: Option<u32>,
+    pub weights: Weights,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Weights {
+    pub garbage_cleared: f32,
+    /// Applied once per placement, so that digging with fewer pieces is preferred.
+    pub piece: f32,
+    pub frames: f32,
+    /// Filled cells above the holes of the highest garbage row.
+    pub covered: f32,
+    /// Holes above the garbage.
+    pub holes: f32,
+    /// Height of the stack above the garbage.
+    pub height: f32,
+    pub height_upper_half: f32,
+    pub bumpiness: f32,
+}
+
+/// How far along digging is since the bot was started.
+#[derive(Serialize, Clone, Copy, Debug)]
+pub struct DigProgress {
+    pub garbage_remaining: u32,
+    pub garbage_cleared: u32,
+    pub pieces: u32,
+    /// Garbage lines left to reach [`Config::lines`], if set.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lines_remaining: Option<u32>,
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            garbage_cleared: 4.0,
+            piece: -1.0,
+            frames: -0.02,
+            covered: -0.8,
+            holes: -2.0,
+            height: -0.1,
+            height_upper_half: -1.5,
+            bumpiness: -0.2,
+        }
+    }
+}
+
+impl Dig {
+    pub fn eligible(options: &BotOptions, state: &GameState) -> bool {
+        options.config.dig.enabled && state.garbage_rows > 0
+    }
+}
+
+impl Evaluator for Dig {
+    fn evaluate(
+        options: &BotOptions,
+        _progress: &Progress,
+        state: GameState,
+        info: &PlacementInfo,
+        cost: MoveCost,
+    ) -> (Eval, Reward) {
+        let height = options.config.rules.board.height as u32;
+        evaluate(&options.config.dig.weights, state, info, cost, height)
+    }
+
+    fn switch(options: &BotOptions, root: &GameState) -> Option<ModeSwitch> {
+        match Dig::eligible(options, root) {
+            true => None,
+            false => Some(ModeSwitch::Freestyle),
+        }
+    }
+}
+
+fn evaluate(
+    weights: &Weights,
+    state: GameState,
+    info: &PlacementInfo,
+    cost: MoveCost,
+    visible_height: u32,
+) -> (Eval, Reward) {
+    let mut eval = 0.0;
+    let mut reward = 0.0;
+
+    reward += weights.garbage_cleared * info.garbage_cleared as f32;
+    reward += weights.piece;
+    reward += weights.frames * cost.frames as f32;
+
+    let garbage = state.garbage_rows as u32;
+
+    // cells in the way of the next garbage clear
+    if garbage > 0 {
+        let top = garbage - 1;
+        let covered: u32 = state
+            .board
+            .cols()
+            .iter()
+            .filter(|&&c| c & 1 << top == 0)
+            .map(|&c| (c >> (top + 1)).count_ones())
+            .sum();
+        eval += weights.covered * covered as f32;
+    }
+
+    // holes in the stack on top of the garbage
+    let (holes, _) = holes(&state.board, garbage, 0);
+    eval += weights.holes * holes as f32;
+
+    // height
+    let highest_point = highest_point(&state.board);
+    eval += weights.height * highest_point.saturating_sub(garbage) as f32;
+    let upper_half = visible_height / 2;
+    if highest_point > upper_half {
+        eval += weights.height_upper_half * (highest_point - upper_half) as f32;
+    }
+
+    // bumpiness
+    eval += weights.bumpiness * bumpiness(&state.board) as f32;
+
+    (
+        Eval { value: eval.into() },
+        Reward {
+            value: reward.into(),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use enumset::EnumSet;
+
+    use super::*;
+
+    /// `rows` garbage rows with a hole in the first column, and what `evaluate` makes of the
+    /// position after a placement that cleared `cleared` garbage rows.
+    fn score(rows: u32, cleared: u32) -> Eval {
+        let mut cols = [(1 << rows) - 1; 10];
+        cols[0] = 0;
+        let state = GameState {
+            board: Board::from_cols(&cols),
+            bag: EnumSet::all(),
+            reserve: Piece::T,
+            back_to_back: 0,
+            combo: 0,
+            garbage: GarbageQueue::default(),
+            hold_locked: false,
+            hold_empty: false,
+            garbage_rows: rows as u8,
+        };
+        let info = PlacementInfo {
+            placement: Placement {
+                location: PieceLocation {
+                    piece: Piece::I,
+                    rotation: Rotation::East,
+                    x: 0,
+                    y: rows as i8 + 1,
+                },
+                spin: Spin::None,
+            },
+            lines_cleared: cleared,
+            combo: cleared,
+            back_to_back: false,
+            perfect_clear: false,
+            attack: 0,
+            garbage_cancelled: 0,
+            garbage_received: 0,
+            garbage_cleared: cleared,
+        };
+        let cost = MoveCost {
+            frames: 10,
+            soft_drops: 0,
+            inputs: 2,
+        };
+        let (eval, reward) = evaluate(&Weights::default(), state, &info, cost, 20);
+        eval + reward
+    }
+
+    #[test]
+    fn fewer_garbage_rows_score_higher() {
+        assert!(score(2, 1) > score(3, 0));
+        assert!(score(1, 2) > score(2, 1));
+        // even without the reward for clearing them
+        assert!(score(2, 0) > score(3, 0));
+    }
+}
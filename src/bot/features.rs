@@ -0,0 +1,124 @@
+use crate::data::*;
+
+fn height(col: u64) -> u32 {
+    64 - col.leading_zeros()
+}
+
+/// Height of the tallest column.
+pub(super) fn highest_point(board: &Board) -> u32 {
+    board.cols().iter().map(|&c| height(c)).max().unwrap_or(0)
+}
+
+/// Holes above row `floor`, and the cells covering them counting at most `max_covered` cells
+/// above each hole.
+pub(super) fn holes(board: &Board, floor: u32, max_covered: u32) -> (u32, u32) {
+    let mut holes = 0;
+    let mut coveredness = 0;
+    for &c in board.cols() {
+        let height = height(c);
+        let mut empty = !c & ((1 << height) - 1) & !((1 << floor) - 1);
+        holes += empty.count_ones();
+        while empty != 0 {
+            coveredness += (height - empty.trailing_zeros()).min(max_covered);
+            empty &= empty - 1;
+        }
+    }
+    (holes, coveredness)
+}
+
+/// Sum of the height differences between neighbouring columns.
+pub(super) fn bumpiness(board: &Board) -> u32 {
+    let cols = board.cols();
+    cols.windows(2)
+        .map(|c| height(c[0]).abs_diff(height(c[1])))
+        .sum()
+}
+
+/// Number of rows filled everywhere but the lowest column, starting from the bottom of it.
+pub(super) fn tetris_well_depth(board: &Board) -> u32 {
+    let cols = board.cols();
+    let (well, well_height) = cols
+        .iter()
+        .map(|&c| height(c))
+        .enumerate()
+        .min_by_key(|&(_, h)| h)
+        .unwrap();
+    let full_except_well = cols
+        .iter()
+        .enumerate()
+        .filter(|&(x, _)| x != well)
+        .fold(!0, |a, (_, &c)| a & c);
+    (full_except_well >> well_height).trailing_ones()
+}
+
+/// Fills up to `count` T slots, returning how many slots clear each number of lines. Slots
+/// clearing more than one line have the lines removed, so that the slots underneath are found.
+pub(super) fn tslots(board: &mut Board, count: usize) -> [u32; 4] {
+    let mut slots = [0; 4];
+    for _ in 0..count {
+        let location = well_known_tslot_left(board).or_else(|| well_known_tslot_right(board));
+        let location = match location {
+            Some(v) => v,
+            None => break,
+        };
+        let mut filled = *board;
+        filled.place(location);
+        let lines = filled.line_clears();
+        slots[lines.count_ones() as usize] += 1;
+        if lines.count_ones() > 1 {
+            filled.remove_lines(lines);
+            *board = filled;
+        }
+    }
+    slots
+}
+
+fn well_known_tslot_left(board: &Board) -> Option<PieceLocation> {
+    for (x, cols) in board.cols().windows(3).enumerate() {
+        let y = 64 - cols[0].leading_zeros();
+        if 64 - cols[1].leading_zeros() >= y {
+            continue;
+        }
+        if !board.occupied((x as i8 + 2, y as i8 - 1)) {
+            continue;
+        }
+        if board.occupied((x as i8 + 2, y as i8)) {
+            continue;
+        }
+        if !board.occupied((x as i8 + 2, y as i8 + 1)) {
+            continue;
+        }
+        return Some(PieceLocation {
+            piece: Piece::T,
+            rotation: Rotation::South,
+            x: x as i8 + 1,
+            y: y as i8,
+        });
+    }
+    None
+}
+
+fn well_known_tslot_right(board: &Board) -> Option<PieceLocation> {
+    for (x, cols) in board.cols().windows(3).enumerate() {
+        let y = 64 - cols[2].leading_zeros();
+        if 64 - cols[1].leading_zeros() >= y {
+            continue;
+        }
+        if !board.occupied((x as i8, y as i8 - 1)) {
+            continue;
+        }
+        if board.occupied((x as i8, y as i8)) {
+            continue;
+        }
+        if !board.occupied((x as i8, y as i8 + 1)) {
+            continue;
+        }
+        return Some(PieceLocation {
+            piece: Piece::T,
+            rotation: Rotation::South,
+            x: x as i8 + 1,
+            y: y as i8,
+        });
+    }
+    None
+}
@@ -0,0 +1,527 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use enumset::EnumSet;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use super::dag_mode::DagMode;
+use super::freestyle::Freestyle;
+use super::{BotOptions, Candidate, Mode, ModeSwitch, PlannedMove, Progress, Statistics, TreeNode};
+use crate::dag::UnknownPiece;
+use crate::data::*;
+use crate::movegen::find_moves;
+use crate::rules::GameRules;
+
+/// Looks for perfect clears while the stack is low, playing freestyle moves when there are none.
+pub struct PerfectClear {
+    freestyle: DagMode<Freestyle>,
+    root: GameState,
+    queue: Vec<Piece>,
+    interrupted: AtomicBool,
+    search: Mutex<SearchState>,
+    /// Flipped on every call to `do_work`, so that the freestyle search gets half of the calls.
+    freestyle_turn: AtomicBool,
+}
+
+/// Nodes the perfect clear search expands in one call to `do_work`, so that it doesn't hold up
+/// updates to the bot.
+const BATCH_NODES: u64 = 250;
+
+/// Values out of range are clamped when read.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "RawConfig")]
+pub struct Config {
+    pub enabled: bool,
+    /// Highest the stack can be for the bot to look for perfect clears, up to the 40 rows the
+    /// bot keeps track of.
+    pub max_height: u32,
+    /// Most pieces a perfect clear may take, counting the current one.
+    pub max_pieces: u32,
+    /// Number of positions to expand before giving up on a search.
+    pub node_limit: u64,
+    /// Perfect clears less likely than this are not played.
+    pub min_probability: f64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Solution {
+    mv: Placement,
+    probability: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawConfig {
+    enabled: bool,
+    max_height: u32,
+    max_pieces: u32,
+    node_limit: u64,
+    min_probability: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            enabled: true,
+            max_height: 4,
+            max_pieces: 10,
+            node_limit: 20_000,
+            min_probability: 0.5,
+        }
+    }
+}
+
+impl Default for RawConfig {
+    fn default() -> Self {
+        let config = Config::default();
+        RawConfig {
+            enabled: config.enabled,
+            max_height: config.max_height,
+            max_pieces: config.max_pieces,
+            node_limit: config.node_limit,
+            min_probability: config.min_probability,
+        }
+    }
+}
+
+impl From<RawConfig> for Config {
+    fn from(raw: RawConfig) -> Self {
+        Config {
+            enabled: raw.enabled,
+            max_height: raw.max_height.min(40),
+            max_pieces: raw.max_pieces,
+            node_limit: raw.node_limit,
+            min_probability: raw.min_probability,
+        }
+    }
+}
+
+impl PerfectClear {
+    /// Looks for perfect clears from the root of `freestyle`, which keeps searching for the moves
+    /// to play when there are none.
+    pub fn new(freestyle: DagMode<Freestyle>, root: GameState, queue: &[Piece]) -> Self {
+        PerfectClear {
+            freestyle,
+            root,
+            queue: queue.to_vec(),
+            interrupted: AtomicBool::new(false),
+            search: Mutex::default(),
+            freestyle_turn: AtomicBool::new(false),
+        }
+    }
+
+    pub fn eligible(options: &BotOptions, state: &GameState) -> bool {
+        let config = &options.config.perfect_clear;
+        config.enabled
+            && state.garbage.is_empty()
+            && state
+                .board
+                .cols()
+                .iter()
+                .all(|&c| 64 - c.leading_zeros() <= config.max_height)
+    }
+
+    /// The freestyle search, for when perfect clears are out of reach.
+    pub fn into_freestyle(self) -> DagMode<Freestyle> {
+        self.freestyle
+    }
+
+    fn invalidate(&mut self) {
+        *self.search.get_mut() = SearchState::default();
+    }
+}
+
+impl Mode for PerfectClear {
+    fn advance(
+        &mut self,
+        options: &BotOptions,
+        mv: Placement,
+        held: bool,
+    ) -> Result<Option<ModeSwitch>, UnknownPiece> {
+        puffin::profile_function!();
+        // the freestyle search is told the same pieces, so it fails first if the queue is empty
+        self.freestyle.advance(options, mv, held)?;
+        self.root
+            .advance(&options.config.rules, self.queue.remove(0), mv, held);
+        self.invalidate();
+        match PerfectClear::eligible(options, &self.root) {
+            true => Ok(None),
+            false => Ok(Some(ModeSwitch::Freestyle)),
+        }
+    }
+
+    fn new_piece(&mut self, options: &BotOptions, piece: Piece) {
+        puffin::profile_function!();
+        self.queue.push(piece);
+        self.freestyle.new_piece(options, piece);
+        self.invalidate();
+    }
+
+    fn suggest(&self, options: &BotOptions) -> Vec<Placement> {
+        puffin::profile_function!();
+        let mut moves = self.freestyle.suggest(options);
+        if let Some(solution) = self.search.lock().best {
+            if solution.probability >= options.config.perfect_clear.min_probability {
+                moves.retain(|&mv| mv != solution.mv);
+                moves.insert(0, solution.mv);
+            }
+        }
+        moves
+    }
+
+    fn candidates(&self, options: &BotOptions, count: usize) -> Vec<Candidate> {
+        self.freestyle.candidates(options, count)
+    }
+
+    fn plan(&self, options: &BotOptions, depth: usize) -> Vec<PlannedMove> {
+        self.freestyle.plan(options, depth)
+    }
+
+    fn tree(&self, options: &BotOptions, depth: usize, breadth: usize) -> TreeNode {
+        self.freestyle.tree(options, depth, breadth)
+    }
+
+    fn is_expected(&self, mv: Placement) -> bool {
+        self.freestyle.is_expected(mv)
+    }
+
+    fn contains(&self, depth: usize, state: &GameState) -> bool {
+        self.freestyle.contains(depth, state)
+    }
+
+    fn reroot(
+        &mut self,
+        options: &BotOptions,
+        depth: usize,
+        state: GameState,
+    ) -> Option<ModeSwitch> {
+        puffin::profile_function!();
+        self.root = state;
+        self.queue.drain(..depth);
+        self.freestyle.reroot(options, depth, state);
+        self.invalidate();
+        match PerfectClear::eligible(options, &self.root) {
+            true => None,
+            false => Some(ModeSwitch::Freestyle),
+        }
+    }
+
+    fn memory(&self) -> usize {
+        self.freestyle.memory()
+    }
+
+    fn compact(&mut self, options: &BotOptions, keep: usize) {
+        self.freestyle.compact(options, keep)
+    }
+
+    fn do_work(&self, options: &BotOptions, progress: &Progress) -> Statistics {
+        puffin::profile_function!();
+        // one thread at a time moves the search along, taking turns with the freestyle search
+        let state = match self.freestyle_turn.fetch_xor(true, Ordering::Relaxed) {
+            true => None,
+            false => self.search.try_lock().filter(|state| !state.done),
+        };
+        let Some(mut state) = state else {
+            return self.freestyle.do_work(options, progress);
+        };
+        self.interrupted.store(false, Ordering::Release);
+
+        let config = &options.config.perfect_clear;
+        let nodes = state.nodes;
+        let mut search = Search {
+            rules: &options.config.rules,
+            queue: &self.queue,
+            speculate: options.speculate,
+            interrupted: &self.interrupted,
+            node_limit: config.node_limit.min(nodes + BATCH_NODES),
+            state: &mut state,
+        };
+        search.run(&self.root, config.max_height, config.max_pieces);
+        if state.nodes >= config.node_limit {
+            state.done = true;
+        }
+
+        Statistics {
+            nodes: state.nodes - nodes,
+            selections: 1,
+            expansions: 1,
+            memory: self.freestyle.memory() as u64,
+        }
+    }
+
+    fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Release);
+    }
+
+    fn perfect_clear_probability(&self) -> Option<f64> {
+        self.search.lock().best.map(|s| s.probability)
+    }
+}
+
+/// How far the perfect clear search has got, kept between calls to `do_work`.
+#[derive(Default)]
+struct SearchState {
+    /// Height of the perfect clear being searched for, or 0 if the search hasn't started.
+    height: u32,
+    /// Whether every height has been searched or the node limit was reached.
+    done: bool,
+    nodes: u64,
+    /// Best move found at the heights searched so far.
+    best: Option<Solution>,
+    moves: AHashMap<(Board, Piece), Arc<[Placement]>>,
+    /// Probabilities found at the current height. Positions whose search was cut off by the end
+    /// of a batch aren't stored, so the next batch picks them up again.
+    memo: AHashMap<Key, f64>,
+}
+
+/// Expectimax search for perfect clears over every piece sequence the bag allows.
+struct Search<'a> {
+    rules: &'a GameRules,
+    queue: &'a [Piece],
+    speculate: bool,
+    interrupted: &'a AtomicBool,
+    node_limit: u64,
+    state: &'a mut SearchState,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Key {
+    board: Board,
+    reserve: Piece,
+    bag: EnumSet<Piece>,
+    hold_locked: bool,
+    hold_empty: bool,
+    depth: usize,
+}
+
+impl Search<'_> {
+    /// Looks for the move most likely to lead to a perfect clear, trying the lowest clears
+    /// first, until every height allowed has been searched or the node limit is reached.
+    fn run(&mut self, root: &GameState, max_height: u32, max_pieces: u32) {
+        if self.queue.is_empty() {
+            self.state.done = true;
+            return;
+        }
+        let highest = root
+            .board
+            .cols()
+            .iter()
+            .map(|&c| 64 - c.leading_zeros())
+            .max()
+            .unwrap();
+        let filled: u32 = root.board.cols().iter().map(|c| c.count_ones()).sum();
+        let width = root.board.width() as u32;
+
+        self.state.height = self.state.height.max(highest.max(1));
+        while self.state.height <= max_height {
+            let height = self.state.height;
+            // a perfect clear at this height takes exactly this many pieces
+            let cells = height * width - filled;
+            if cells.is_multiple_of(4)
+                && cells / 4 <= max_pieces
+                && fillable(&root.board, height as i8)
+            {
+                let (probability, mv) = match self.choose(root, 0, height as i8) {
+                    Some(v) => v,
+                    None => return,
+                };
+                if let Some(mv) = mv {
+                    if self.state.best.is_none_or(|b| probability > b.probability) {
+                        self.state.best = Some(Solution { mv, probability });
+                    }
+                    if probability >= 1.0 {
+                        break;
+                    }
+                }
+                self.state.memo.clear();
+            }
+            self.state.height += 1;
+        }
+        self.state.done = true;
+    }
+
+    /// Probability of a perfect clear from `state` with `depth` pieces placed so far.
+    fn value(&mut self, state: &GameState, depth: usize, height: i8) -> Option<f64> {
+        let key = Key {
+            board: state.board,
+            reserve: state.reserve,
+            bag: state.bag,
+            hold_locked: state.hold_locked,
+            hold_empty: state.hold_empty,
+            depth,
+        };
+        if let Some(&v) = self.state.memo.get(&key) {
+            return Some(v);
+        }
+
+        let v = match self.queue.get(depth) {
+            Some(_) => self.choose(state, depth, height)?.0,
+            None => {
+                let bag = match self.speculate {
+                    true => state.bag,
+                    false => EnumSet::all(),
+                };
+                let mut total = 0.0;
+                for next in bag {
+                    total += self.choose_with(state, next, depth, height)?.0;
+                }
+                total / bag.len() as f64
+            }
+        };
+
+        self.state.memo.insert(key, v);
+        Some(v)
+    }
+
+    fn choose(
+        &mut self,
+        state: &GameState,
+        depth: usize,
+        height: i8,
+    ) -> Option<(f64, Option<Placement>)> {
+        self.choose_with(state, self.queue[depth], depth, height)
+    }
+
+    /// Best placement and its probability of a perfect clear when `next` is the next piece.
+    fn choose_with(
+        &mut self,
+        state: &GameState,
+        next: Piece,
+        depth: usize,
+        height: i8,
+    ) -> Option<(f64, Option<Placement>)> {
+        if self.state.nodes >= self.node_limit || self.interrupted.load(Ordering::Acquire) {
+            return None;
+        }
+        self.state.nodes += 1;
+
+        let mut pieces = EnumSet::only(next);
+        if state.can_hold(self.rules) {
+            pieces |= state.reserve;
+        }
+
+        let mut best = (0.0, None);
+        for piece in pieces {
+            for &mv in self.moves(&state.board, piece).iter() {
+                if mv.location.cells().iter().any(|&(_, y)| y >= height) {
+                    continue;
+                }
+                let mut child = *state;
+                let info = child.advance(self.rules, next, mv, state.held_by(mv, next));
+                let probability = if info.perfect_clear {
+                    1.0
+                } else {
+                    let height = height - info.lines_cleared as i8;
+                    if !fillable(&child.board, height) {
+                        continue;
+                    }
+                    self.value(&child, depth + 1, height)?
+                };
+                if probability > best.0 {
+                    best = (probability, Some(mv));
+                    if probability >= 1.0 {
+                        return Some(best);
+                    }
+                }
+            }
+        }
+        Some(best)
+    }
+
+    fn moves(&mut self, board: &Board, piece: Piece) -> Arc<[Placement]> {
+        let rules = self.rules;
+        self.state
+            .moves
+            .entry((*board, piece))
+            .or_insert_with(|| {
+                find_moves(board, piece, rules)
+                    .into_iter()
+                    .map(|(mv, _)| mv)
+                    .collect()
+            })
+            .clone()
+    }
+}
+
+/// Whether every enclosed area of empty cells below `height` could be filled by whole pieces.
+fn fillable(board: &Board, height: i8) -> bool {
+    if height <= 0 {
+        return false;
+    }
+    let rows = (1u64 << height) - 1;
+    let mut empty = [0; MAX_WIDTH];
+    for (e, &c) in empty.iter_mut().zip(board.cols()) {
+        *e = !c & rows;
+    }
+
+    let width = board.width() as usize;
+    for x in 0..width {
+        while empty[x] != 0 {
+            let mut stack = vec![(x, empty[x].trailing_zeros() as i8)];
+            empty[x] &= empty[x] - 1;
+            let mut size = 0;
+            while let Some((x, y)) = stack.pop() {
+                size += 1;
+                let neighbours = [(x.wrapping_sub(1), y), (x + 1, y), (x, y - 1), (x, y + 1)];
+                for (nx, ny) in neighbours {
+                    if nx < width && (0..height).contains(&ny) && empty[nx] & 1 << ny != 0 {
+                        empty[nx] &= !(1 << ny);
+                        stack.push((nx, ny));
+                    }
+                }
+            }
+            if size % 4 != 0 {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bot::BotConfig;
+
+    #[test]
+    fn finds_a_four_line_perfect_clear() {
+        let options = BotOptions {
+            speculate: false,
+            config: Arc::new(BotConfig::default()),
+        };
+        let root = GameState {
+            board: Board::new(10),
+            bag: EnumSet::all(),
+            reserve: Piece::I,
+            back_to_back: 0,
+            combo: 0,
+            garbage: GarbageQueue::default(),
+            hold_locked: false,
+            hold_empty: false,
+            garbage_rows: 0,
+        };
+        // ten I pieces can't clear two lines, but fill four
+        let queue = [Piece::I; 10];
+        let freestyle = DagMode::new(&options, root, &queue);
+        let pc = PerfectClear::new(freestyle, root, &queue);
+        while !pc.search.lock().done {
+            pc.do_work(&options, &Progress::default());
+        }
+        assert_eq!(pc.search.lock().height, 4);
+        assert_eq!(pc.perfect_clear_probability(), Some(1.0));
+
+        let mv = pc.suggest(&options)[0];
+        let mut state = root;
+        state.advance(&options.config.rules, Piece::I, mv, false);
+        assert!(fillable(&state.board, 4));
+    }
+
+    #[test]
+    fn max_height_is_clamped_to_the_tracked_rows() {
+        let config: Config = serde_json::from_str(r#"{"max_height": 64}"#).unwrap();
+        assert_eq!(config.max_height, 40);
+        assert!(config.enabled);
+    }
+}
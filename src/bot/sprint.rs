@@ -0,0 +1,116 @@
+use serde::{Deserialize, Serialize};
+
+use super::dag_mode::{Eval, Evaluator, Reward};
+use super::features::*;
+use super::{BotOptions, ModeSwitch, Progress};
+use crate::data::*;
+use crate::movegen::MoveCost;
+
+/// Clears a set number of lines in as few pieces and frames as possible.
+pub struct Sprint;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    pub lines: u32,
+    pub weights: Weights,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Weights {
+    /// Only lines up to the number left to clear are rewarded.
+    pub line_cleared: f32,
+    pub piece: f32,
+    pub frames: f32,
+    pub holes: f32,
+    pub cell_coveredness: f32,
+    pub height: f32,
+    pub height_upper_half: f32,
+    pub bumpiness: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            enabled: false,
+            lines: 40,
+            weights: Weights::default(),
+        }
+    }
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            line_cleared: 1.0,
+            piece: -0.5,
+            frames: -0.05,
+            holes: -3.0,
+            cell_coveredness: -0.3,
+            height: -0.1,
+            height_upper_half: -1.5,
+            bumpiness: -0.3,
+        }
+    }
+}
+
+impl Evaluator for Sprint {
+    fn evaluate(
+        options: &BotOptions,
+        progress: &Progress,
+        state: GameState,
+        info: &PlacementInfo,
+        cost: MoveCost,
+    ) -> (Eval, Reward) {
+        let config = &options.config.sprint;
+        let height = options.config.rules.board.height as u32;
+        // lines cleared earlier in the tree aren't known, so this overestimates deeper down
+        let lines_left = config.lines.saturating_sub(progress.lines_cleared);
+        evaluate(&config.weights, state, info, cost, lines_left, height)
+    }
+
+    fn switch(_options: &BotOptions, _root: &GameState) -> Option<ModeSwitch> {
+        None
+    }
+}
+
+fn evaluate(
+    weights: &Weights,
+    state: GameState,
+    info: &PlacementInfo,
+    cost: MoveCost,
+    lines_left: u32,
+    visible_height: u32,
+) -> (Eval, Reward) {
+    let mut eval = 0.0;
+    let mut reward = 0.0;
+
+    reward += weights.line_cleared * info.lines_cleared.min(lines_left) as f32;
+    reward += weights.piece;
+    reward += weights.frames * cost.frames as f32;
+
+    // holes and the cells covering them
+    let (holes, coveredness) = holes(&state.board, 0, u32::MAX);
+    eval += weights.holes * holes as f32;
+    eval += weights.cell_coveredness * coveredness as f32;
+
+    // height
+    let highest_point = highest_point(&state.board);
+    eval += weights.height * highest_point as f32;
+    let upper_half = visible_height / 2;
+    if highest_point > upper_half {
+        eval += weights.height_upper_half * (highest_point - upper_half) as f32;
+    }
+
+    // bumpiness
+    eval += weights.bumpiness * bumpiness(&state.board) as f32;
+
+    (
+        Eval { value: eval.into() },
+        Reward {
+            value: reward.into(),
+        },
+    )
+}
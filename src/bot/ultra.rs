@@ -0,0 +1,188 @@
+use serde::{Deserialize, Serialize};
+
+use super::dag_mode::{Eval, Evaluator, Reward};
+use super::features::*;
+use super::{BotOptions, ModeSwitch, Progress};
+use crate::data::*;
+use crate::movegen::MoveCost;
+
+/// Scores as many points as possible before running out of pieces or time.
+pub struct Ultra;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    pub enabled: bool,
+    /// Number of pieces the bot has to score with.
+    pub pieces: Option<u32>,
+    /// Number of frames the bot has to score in, counting only the inputs of its moves.
+    pub frames: Option<u32>,
+    pub weights: Weights,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Weights {
+    /// Applied to the points scored, using guideline scoring at level 1.
+    pub score: f32,
+    pub frames: f32,
+    pub holes: f32,
+    pub cell_coveredness: f32,
+    pub height: f32,
+    pub height_upper_half: f32,
+    pub bumpiness: f32,
+    pub tetris_well_depth: f32,
+    pub tslot: [f32; 4],
+    pub has_back_to_back: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            enabled: false,
+            pieces: None,
+            frames: Some(3 * 60 * 60),
+            weights: Weights::default(),
+        }
+    }
+}
+
+impl Default for Weights {
+    fn default() -> Self {
+        Weights {
+            score: 0.01,
+            frames: -0.02,
+            holes: -1.5,
+            cell_coveredness: -0.2,
+            height: -0.2,
+            height_upper_half: -1.5,
+            bumpiness: -0.2,
+            tetris_well_depth: 0.3,
+            tslot: [0.1, 1.5, 2.0, 4.0],
+            has_back_to_back: 1.0,
+        }
+    }
+}
+
+impl Config {
+    /// Estimate of the number of pieces left to score with.
+    fn pieces_left(&self, progress: &Progress) -> Option<u32> {
+        let by_pieces = self.pieces.map(|p| p.saturating_sub(progress.pieces));
+        let by_frames = self.frames.map(|f| {
+            let per_piece = match progress.pieces {
+                0 => 10,
+                n => (progress.frames / n).max(1),
+            };
+            f.saturating_sub(progress.frames) / per_piece
+        });
+        match (by_pieces, by_frames) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        }
+    }
+}
+
+impl Evaluator for Ultra {
+    fn evaluate(
+        options: &BotOptions,
+        progress: &Progress,
+        state: GameState,
+        info: &PlacementInfo,
+        cost: MoveCost,
+    ) -> (Eval, Reward) {
+        let config = &options.config.ultra;
+        let height = options.config.rules.board.height as u32;
+        // the shape of the stack is only worth something while there are pieces left to use it
+        let urgency = match config.pieces_left(progress) {
+            Some(left) => (left as f32 / 7.0).min(1.0),
+            None => 1.0,
+        };
+        evaluate(&config.weights, state, info, cost, urgency, height)
+    }
+
+    fn switch(_options: &BotOptions, _root: &GameState) -> Option<ModeSwitch> {
+        None
+    }
+}
+
+/// Points for a placement under guideline scoring at level 1, not counting drops.
+fn score(info: &PlacementInfo) -> u32 {
+    let lines = info.lines_cleared as usize;
+    let base = match info.placement.spin {
+        Spin::None => [0, 100, 300, 500, 800].get(lines),
+        Spin::Mini => [100, 200, 400].get(lines),
+        Spin::Full => [400, 800, 1200, 1600].get(lines),
+    }
+    .copied()
+    .unwrap_or(0);
+    let mut score = match info.back_to_back {
+        true => base * 3 / 2,
+        false => base,
+    };
+    if info.lines_cleared > 0 {
+        score += 50 * info.combo.saturating_sub(1);
+    }
+    if info.perfect_clear {
+        score += match (lines, info.back_to_back) {
+            (4, true) => 3200,
+            _ => [0, 800, 1200, 1800, 2000]
+                .get(lines)
+                .copied()
+                .unwrap_or(2000),
+        };
+    }
+    score
+}
+
+fn evaluate(
+    weights: &Weights,
+    mut state: GameState,
+    info: &PlacementInfo,
+    cost: MoveCost,
+    urgency: f32,
+    visible_height: u32,
+) -> (Eval, Reward) {
+    let mut eval = 0.0;
+    let mut reward = 0.0;
+
+    reward += weights.score * score(info) as f32;
+    reward += weights.frames * cost.frames as f32;
+
+    if state.back_to_back > 0 {
+        eval += weights.has_back_to_back;
+    }
+
+    // tslots
+    let slots = tslots(&mut state.board, 1 + state.bag.contains(Piece::T) as usize);
+    for (&weight, count) in weights.tslot.iter().zip(slots) {
+        eval += weight * count as f32;
+    }
+
+    // holes and the cells covering them
+    let (holes, coveredness) = holes(&state.board, 0, u32::MAX);
+    eval += weights.holes * holes as f32;
+    eval += weights.cell_coveredness * coveredness as f32;
+
+    // tetris well depth
+    eval += weights.tetris_well_depth * tetris_well_depth(&state.board) as f32;
+
+    // height
+    let highest_point = highest_point(&state.board);
+    eval += weights.height * highest_point as f32;
+    let upper_half = visible_height / 2;
+    if highest_point > upper_half {
+        eval += weights.height_upper_half * (highest_point - upper_half) as f32;
+    }
+
+    // bumpiness
+    eval += weights.bumpiness * bumpiness(&state.board) as f32;
+
+    (
+        Eval {
+            value: (eval * urgency).into(),
+        },
+        Reward {
+            value: reward.into(),
+        },
+    )
+}
@@ -1,10 +1,16 @@
+use std::sync::atomic::{self, AtomicU32};
+
+use ahash::AHashMap;
 use bumpalo_herd::Herd;
 use enum_map::EnumMap;
 use once_cell::sync::Lazy;
 use ouroboros::self_referencing;
+use rand::prelude::*;
+use serde::{Deserialize, Serialize};
 
 use crate::data::Placement;
 use crate::data::{GameState, Piece};
+use crate::rules::GameRules;
 
 mod known;
 mod speculated;
@@ -15,8 +21,69 @@ pub trait Evaluation:
     type Reward: Copy;
 
     fn average(of: impl Iterator<Item = Option<Self>>) -> Self;
+
+    /// The evaluation as a number, for selection policies that do arithmetic with it.
+    fn value(self) -> f64;
+}
+
+/// How the search picks the move to look further into at each position. Parameters out of range
+/// are clamped when read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "type", rename_all = "snake_case", from = "RawSelectionPolicy")]
+pub enum SelectionPolicy {
+    /// Picks the move at a random rank, with lower ranks exponentially more likely depending on
+    /// the exploitation setting of the mode.
+    #[default]
+    Rank,
+    /// UCB1 over the evaluations of the moves, rescaled so that the best move is worth 1 and the
+    /// worst 0.
+    Uct { exploration: f64 },
+    /// UCB1 weighted by a prior, as in AlphaZero. The prior is a softmax over the static
+    /// evaluations of the moves.
+    Puct { exploration: f64, temperature: f64 },
+    /// Picks the best move after adding gaussian noise to each rescaled evaluation, with a
+    /// standard deviation of `spread` that shrinks as the move is visited more.
+    Thompson { spread: f64 },
 }
 
+/// Lowest softmax temperature, so that working out the prior never divides by zero.
+const MIN_TEMPERATURE: f64 = 1e-3;
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RawSelectionPolicy {
+    Rank,
+    Uct { exploration: f64 },
+    Puct { exploration: f64, temperature: f64 },
+    Thompson { spread: f64 },
+}
+
+impl From<RawSelectionPolicy> for SelectionPolicy {
+    fn from(raw: RawSelectionPolicy) -> Self {
+        // max also turns NaN into the bound
+        match raw {
+            RawSelectionPolicy::Rank => SelectionPolicy::Rank,
+            RawSelectionPolicy::Uct { exploration } => SelectionPolicy::Uct {
+                exploration: exploration.max(0.0),
+            },
+            RawSelectionPolicy::Puct {
+                exploration,
+                temperature,
+            } => SelectionPolicy::Puct {
+                exploration: exploration.max(0.0),
+                temperature: temperature.max(MIN_TEMPERATURE),
+            },
+            RawSelectionPolicy::Thompson { spread } => SelectionPolicy::Thompson {
+                spread: spread.max(0.0),
+            },
+        }
+    }
+}
+
+/// The next piece isn't known to the search, so it can't follow a move played from the root.
+#[derive(Debug)]
+pub struct UnknownPiece;
+
 pub struct Dag<E: Evaluation> {
     root: GameState,
     top_layer: Box<LayerCommon<E>>,
@@ -27,6 +94,47 @@ pub struct Selection<'a, E: Evaluation> {
     game_state: GameState,
 }
 
+/// A move from the root with what the search knows about it.
+pub struct Candidate<E: Evaluation> {
+    pub mv: Placement,
+    pub eval: E,
+    /// Number of selections that passed through the resulting position.
+    pub visits: u32,
+    /// Number of expansions below the resulting position.
+    pub expansions: u32,
+    /// The best known placements following this move.
+    pub pv: Vec<Placement>,
+}
+
+/// A step of the line the search expects to be played.
+pub struct PlannedMove<E: Evaluation> {
+    pub piece: Piece,
+    /// Whether the piece is a guess, because it isn't in the queue yet.
+    pub speculated: bool,
+    pub mv: Placement,
+    pub eval: E,
+    pub state: GameState,
+}
+
+/// A position in an export of the search tree.
+pub struct TreeNode<E: Evaluation> {
+    pub state: GameState,
+    pub eval: E,
+    pub visits: u32,
+    pub expansions: u32,
+    pub children: Vec<TreeEdge<E>>,
+}
+
+pub struct TreeEdge<E: Evaluation> {
+    pub piece: Piece,
+    pub speculated: bool,
+    pub mv: Placement,
+    pub eval: E,
+    /// Number of selections that went through the move.
+    pub visits: u32,
+    pub node: TreeNode<E>,
+}
+
 pub struct ChildData<E: Evaluation> {
     pub resulting_state: GameState,
     pub mv: Placement,
@@ -53,11 +161,34 @@ enum LayerKind<'bump, E: Evaluation> {
     Speculated(speculated::Layer<'bump, E>),
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Default)]
+struct NodeStats<E: Evaluation> {
+    eval: E,
+    visits: u32,
+    expansions: u32,
+}
+
+#[derive(Debug)]
 struct Child<E: Evaluation> {
     mv: Placement,
     reward: E::Reward,
     cached_eval: E,
+    /// Evaluation of the move before anything after it was searched.
+    static_eval: E,
+    /// Number of selections that went through the move.
+    visits: AtomicU32,
+}
+
+impl<E: Evaluation> Clone for Child<E> {
+    fn clone(&self) -> Self {
+        Child {
+            mv: self.mv,
+            reward: self.reward,
+            cached_eval: self.cached_eval,
+            static_eval: self.static_eval,
+            visits: AtomicU32::new(self.visits.load(atomic::Ordering::Relaxed)),
+        }
+    }
 }
 
 enum SelectResult {
@@ -66,6 +197,9 @@ enum SelectResult {
     Advance(Piece, Placement),
 }
 
+/// A position kept when compacting a layer, with the kept edges leading to it.
+type KeptNode = (GameState, Vec<(u64, Placement, Piece)>);
+
 struct BackpropUpdate {
     parent: u64,
     speculation_piece: Piece,
@@ -90,30 +224,54 @@ impl<E: Evaluation> Dag<E> {
         }
     }
 
-    pub fn advance(&mut self, mv: Placement) {
+    pub fn root(&self) -> &GameState {
+        &self.root
+    }
+
+    /// Moves the root along `mv`. `held` is whether the player pressed hold for it. Fails without
+    /// changing anything if the search hasn't been told the next piece.
+    pub fn advance(
+        &mut self,
+        rules: &GameRules,
+        mv: Placement,
+        held: bool,
+    ) -> Result<(), UnknownPiece> {
         puffin::profile_function!();
+        let next = self.top_layer.kind.piece().ok_or(UnknownPiece)?;
         let top_layer = std::mem::take(&mut *self.top_layer);
-        self.root.advance(
-            top_layer
-                .kind
-                .piece()
-                .expect("cannot advance without next piece"),
-            mv,
-        );
+        self.root.advance(rules, next, mv, held);
         Lazy::force(&top_layer.next_layer);
         self.top_layer = Lazy::into_value(top_layer.next_layer).unwrap();
         self.top_layer.kind.initialize_root(&self.root);
+        Ok(())
     }
 
     pub fn add_piece(&mut self, piece: Piece) {
         puffin::profile_function!();
+        let mut depth = 0;
         let mut layer = &mut self.top_layer;
-        loop {
-            if layer.kind.despeculate(piece) {
-                // TODO: backprop despeculated values
-                return;
+        let mut updates = loop {
+            if let Some(updates) = layer.kind.despeculate(piece) {
+                break updates;
             }
+            depth += 1;
             layer = &mut layer.next_layer;
+        };
+
+        // the layers above still hold values averaged over the pieces that could have come
+        puffin::profile_scope!("backprop");
+        let mut layers = vec![&*self.top_layer];
+        for _ in 0..depth {
+            let next = &*layers.last().unwrap().next_layer;
+            layers.push(next);
+        }
+        let mut next_layer = layers.pop().unwrap();
+        while let Some(layer) = layers.pop() {
+            if updates.is_empty() {
+                break;
+            }
+            updates = layer.kind.backprop(updates, next_layer);
+            next_layer = layer;
         }
     }
 
@@ -122,23 +280,247 @@ impl<E: Evaluation> Dag<E> {
         self.top_layer.kind.suggest(&self.root)
     }
 
-    pub fn select(&self, speculate: bool, exploration: f64) -> Option<Selection<E>> {
+    /// Whether `state` is a position the search reached `depth` moves after the root.
+    pub fn contains(&self, depth: usize, state: &GameState) -> bool {
+        let mut layer = &*self.top_layer;
+        for _ in 0..depth {
+            match Lazy::get(&layer.next_layer) {
+                Some(next) => layer = next,
+                None => return false,
+            }
+        }
+        layer.kind.stats(state).is_some()
+    }
+
+    /// Makes `state`, a position the search reached `depth` moves after the root, the new root.
+    /// Everything the search found about positions reachable from it is kept.
+    pub fn reroot(&mut self, depth: usize, state: GameState) {
+        puffin::profile_function!();
+        for _ in 0..depth {
+            let top_layer = std::mem::take(&mut *self.top_layer);
+            Lazy::force(&top_layer.next_layer);
+            self.top_layer = Lazy::into_value(top_layer.next_layer).unwrap();
+        }
+        self.root = state;
+        self.top_layer.kind.initialize_root(&self.root);
+    }
+
+    /// Whether playing `mv` follows the search, meaning it is one of the moves from the root the
+    /// search looked at, or the search hasn't looked at any yet.
+    pub fn is_expected(&self, mv: Placement) -> bool {
+        let children = self.top_layer.kind.children(&self.root);
+        let target = mv.location.canonical_form();
+        children.is_empty()
+            || children
+                .iter()
+                .any(|(_, c)| c.mv.location.canonical_form() == target && c.mv.spin == mv.spin)
+    }
+
+    /// Approximate number of bytes used by the search tree.
+    pub fn memory(&self) -> usize {
+        let mut memory = 0;
+        let mut layer = &*self.top_layer;
+        loop {
+            memory += layer.kind.memory();
+            match Lazy::get(&layer.next_layer) {
+                Some(next) => layer = next,
+                None => return memory,
+            }
+        }
+    }
+
+    /// Rebuilds the layers in fresh arenas, keeping only the positions that can still be reached
+    /// from the root through the `keep` best children of each position for each possible next
+    /// piece. Positions left over from before earlier moves are dropped either way.
+    pub fn compact(&mut self, rules: &GameRules, keep: usize) {
+        puffin::profile_function!();
+        let mut nodes = vec![(self.root, vec![])];
+        let mut layer = &mut *self.top_layer;
+        loop {
+            let mut kept = vec![];
+            layer.kind = layer.kind.compact(&nodes, keep, &mut kept);
+            let next = match Lazy::get(&layer.next_layer) {
+                Some(next) => &next.kind,
+                None => return,
+            };
+
+            let mut next_nodes: Vec<KeptNode> = vec![];
+            let mut indices = AHashMap::new();
+            for (parent, piece, mv) in kept {
+                let mut state = parent;
+                state.advance(rules, piece, mv, state.held_by(mv, piece));
+                let i = *indices.entry(next.index(&state)).or_insert_with(|| {
+                    next_nodes.push((state, vec![]));
+                    next_nodes.len() - 1
+                });
+                next_nodes[i].1.push((layer.kind.index(&parent), mv, piece));
+            }
+            nodes = next_nodes;
+            layer = &mut layer.next_layer;
+        }
+    }
+
+    pub fn select(
+        &self,
+        rules: &GameRules,
+        speculate: bool,
+        policy: SelectionPolicy,
+        exploration: f64,
+    ) -> Option<Selection<'_, E>> {
         puffin::profile_function!();
         let mut layers = vec![&*self.top_layer];
-        let mut game_state = self.root;
+        let mut states = vec![self.root];
         loop {
             let &layer = layers.last().unwrap();
+            let mut game_state = *states.last().unwrap();
 
-            match layer.kind.select(&game_state, speculate, exploration) {
+            match layer
+                .kind
+                .select(&game_state, speculate, policy, exploration)
+            {
                 SelectResult::Failed => return None,
-                SelectResult::Done => return Some(Selection { layers, game_state }),
+                SelectResult::Done => {
+                    for (layer, state) in layers.iter().zip(&states) {
+                        layer.kind.add_expansion(state);
+                    }
+                    return Some(Selection { layers, game_state });
+                }
                 SelectResult::Advance(next, placement) => {
-                    game_state.advance(next, placement);
+                    game_state.advance(rules, next, placement, game_state.held_by(placement, next));
                     layers.push(&layer.next_layer);
+                    states.push(game_state);
                 }
             }
         }
     }
+
+    /// The `count` best moves from the root, best first.
+    pub fn candidates(&self, rules: &GameRules, count: usize) -> Vec<Candidate<E>> {
+        puffin::profile_function!();
+        let mut children = self.top_layer.kind.children(&self.root);
+        children.sort_by_key(|(_, c)| std::cmp::Reverse(c.cached_eval));
+
+        let mut candidates: Vec<Candidate<E>> = vec![];
+        for (piece, child) in children {
+            if candidates.len() == count {
+                break;
+            }
+            // moves using the reserve piece appear once for every possible next piece
+            if candidates.iter().any(|c| c.mv == child.mv) {
+                continue;
+            }
+            let mut state = self.root;
+            state.advance(rules, piece, child.mv, state.held_by(child.mv, piece));
+            let layer = &*self.top_layer.next_layer;
+            let stats = layer.kind.stats(&state).unwrap_or_default();
+            candidates.push(Candidate {
+                mv: child.mv,
+                eval: child.cached_eval,
+                visits: stats.visits,
+                expansions: stats.expansions,
+                pv: principal_variation(layer, state, rules),
+            });
+        }
+        candidates
+    }
+
+    /// The line the search expects to be played, up to `depth` moves long. Where the next piece
+    /// isn't known, the line continues with the piece whose best move has the median evaluation.
+    pub fn plan(&self, rules: &GameRules, depth: usize) -> Vec<PlannedMove<E>> {
+        puffin::profile_function!();
+        let mut plan = vec![];
+        let mut layer = &*self.top_layer;
+        let mut state = self.root;
+        while plan.len() < depth {
+            // the best move for each possible next piece
+            let mut best: Vec<(Piece, Child<E>)> = vec![];
+            for (piece, child) in layer.kind.children(&state) {
+                if best.iter().all(|&(p, _)| p != piece) {
+                    best.push((piece, child));
+                }
+            }
+            best.sort_by_key(|(_, c)| c.cached_eval);
+            let (piece, child) = match best.get(best.len() / 2) {
+                Some(v) => v.clone(),
+                None => break,
+            };
+
+            state.advance(rules, piece, child.mv, state.held_by(child.mv, piece));
+            plan.push(PlannedMove {
+                piece,
+                speculated: layer.kind.piece().is_none(),
+                mv: child.mv,
+                eval: child.cached_eval,
+                state,
+            });
+            layer = &layer.next_layer;
+        }
+        plan
+    }
+
+    /// Exports the search tree up to `depth` moves deep, keeping only the `breadth` best moves
+    /// for each possible next piece.
+    pub fn tree(&self, rules: &GameRules, depth: usize, breadth: usize) -> TreeNode<E> {
+        puffin::profile_function!();
+        export_tree(&self.top_layer, self.root, rules, depth, breadth)
+    }
+}
+
+fn export_tree<E: Evaluation>(
+    layer: &LayerCommon<E>,
+    state: GameState,
+    rules: &GameRules,
+    depth: usize,
+    breadth: usize,
+) -> TreeNode<E> {
+    let stats = layer.kind.stats(&state).unwrap_or_default();
+    let mut children = vec![];
+    if depth > 0 {
+        let speculated = layer.kind.piece().is_none();
+        let mut taken = EnumMap::<Piece, usize>::default();
+        for (piece, child) in layer.kind.children(&state) {
+            if taken[piece] == breadth {
+                continue;
+            }
+            taken[piece] += 1;
+            let mut next = state;
+            next.advance(rules, piece, child.mv, next.held_by(child.mv, piece));
+            children.push(TreeEdge {
+                piece,
+                speculated,
+                mv: child.mv,
+                eval: child.cached_eval,
+                visits: child.visits.load(atomic::Ordering::Relaxed),
+                node: export_tree(&layer.next_layer, next, rules, depth - 1, breadth),
+            });
+        }
+    }
+    TreeNode {
+        state,
+        eval: stats.eval,
+        visits: stats.visits,
+        expansions: stats.expansions,
+        children,
+    }
+}
+
+/// Follows the best children from `state` for as long as the next piece is known.
+fn principal_variation<E: Evaluation>(
+    mut layer: &LayerCommon<E>,
+    mut state: GameState,
+    rules: &GameRules,
+) -> Vec<Placement> {
+    let mut pv = vec![];
+    while let Some(piece) = layer.kind.piece() {
+        let best = match layer.kind.children(&state).first() {
+            Some((_, child)) => child.clone(),
+            None => break,
+        };
+        state.advance(rules, piece, best.mv, state.held_by(best.mv, piece));
+        pv.push(best.mv);
+        layer = &layer.next_layer;
+    }
+    pv
 }
 
 impl<E: Evaluation> Selection<'_, E> {
@@ -168,27 +550,26 @@ impl<E: Evaluation> Selection<'_, E> {
 }
 
 fn update_child<E: Evaluation>(list: &mut [Child<E>], placement: Placement, child_eval: E) -> bool {
-    let mut index = list
-        .iter()
-        .enumerate()
-        .find_map(|(i, c)| (c.mv == placement).then(|| i))
-        .unwrap();
+    let Some(mut index) = list.iter().position(|c| c.mv == placement) else {
+        // a link to a move the list doesn't have can't change which move is best
+        return false;
+    };
 
     list[index].cached_eval = child_eval + list[index].reward;
 
     if index > 0 && list[index - 1].cached_eval < list[index].cached_eval {
         // Shift up until the list is in order
-        let hole = list[index];
+        let hole = list[index].clone();
         while index > 0 && list[index - 1].cached_eval < hole.cached_eval {
-            list[index] = list[index - 1];
+            list[index] = list[index - 1].clone();
             index -= 1;
         }
         list[index] = hole;
     } else if index < list.len() - 1 && list[index + 1].cached_eval > list[index].cached_eval {
         // Shift down until the list is in order
-        let hole = list[index];
+        let hole = list[index].clone();
         while index < list.len() - 1 && list[index + 1].cached_eval > hole.cached_eval {
-            list[index] = list[index + 1];
+            list[index] = list[index + 1].clone();
             index += 1;
         }
         list[index] = hole;
@@ -197,6 +578,71 @@ fn update_child<E: Evaluation>(list: &mut [Child<E>], placement: Placement, chil
     index == 0
 }
 
+/// Picks the child to select from a list ordered best first, and counts the visit.
+fn select_child<E: Evaluation>(
+    list: &[Child<E>],
+    policy: SelectionPolicy,
+    exploitation: f64,
+) -> &Child<E> {
+    let mut rng = thread_rng();
+    let best = list[0].cached_eval.value();
+    let range = best - list[list.len() - 1].cached_eval.value();
+    let rescaled = |c: &Child<E>| match range > 0.0 {
+        true => 1.0 - (best - c.cached_eval.value()) / range,
+        false => 1.0,
+    };
+    let visits = |c: &Child<E>| c.visits.load(atomic::Ordering::Relaxed) as f64;
+    let total = || list.iter().map(visits).sum::<f64>() + 1.0;
+
+    let child = match policy {
+        SelectionPolicy::Rank => {
+            let s: f64 = rng.gen();
+            &list[((-s.ln() / exploitation) % list.len() as f64) as usize]
+        }
+        SelectionPolicy::Uct { exploration } => {
+            let ln_total = total().ln();
+            highest_scoring(list, |c| match visits(c) {
+                0.0 => f64::INFINITY,
+                n => rescaled(c) + exploration * (ln_total / n).sqrt(),
+            })
+        }
+        SelectionPolicy::Puct {
+            exploration,
+            temperature,
+        } => {
+            let best_static = list.iter().map(|c| c.static_eval).max().unwrap().value();
+            let weight = |c: &Child<E>| ((c.static_eval.value() - best_static) / temperature).exp();
+            let sum: f64 = list.iter().map(weight).sum();
+            let sqrt_total = total().sqrt();
+            highest_scoring(list, |c| {
+                let prior = weight(c) / sum;
+                rescaled(c) + exploration * prior * sqrt_total / (1.0 + visits(c))
+            })
+        }
+        SelectionPolicy::Thompson { spread } => highest_scoring(list, |c| {
+            // Box-Muller transform
+            let u: f64 = 1.0 - rng.gen::<f64>();
+            let v: f64 = rng.gen();
+            let noise = (-2.0 * u.ln()).sqrt() * (std::f64::consts::TAU * v).cos();
+            rescaled(c) + spread * noise / (1.0 + visits(c)).sqrt()
+        }),
+    };
+    child.visits.fetch_add(1, atomic::Ordering::Relaxed);
+    child
+}
+
+/// The first of the highest scoring children, so that ties go to the better move.
+fn highest_scoring<E: Evaluation>(
+    list: &[Child<E>],
+    mut score: impl FnMut(&Child<E>) -> f64,
+) -> &Child<E> {
+    list.iter()
+        .map(|c| (c, score(c)))
+        .min_by(|a, b| b.1.total_cmp(&a.1))
+        .unwrap()
+        .0
+}
+
 impl<E: Evaluation> WithBump<E> {
     fn initialize_root(&self, root: &GameState) {
         self.with(|this| match this.data {
@@ -217,6 +663,27 @@ impl<E: Evaluation> WithBump<E> {
         })
     }
 
+    fn children(&self, state: &GameState) -> Vec<(Piece, Child<E>)> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.children(state),
+            LayerKind::Speculated(l) => l.children(state),
+        })
+    }
+
+    fn stats(&self, state: &GameState) -> Option<NodeStats<E>> {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.stats(state),
+            LayerKind::Speculated(l) => l.stats(state),
+        })
+    }
+
+    fn add_expansion(&self, state: &GameState) {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.add_expansion(state),
+            LayerKind::Speculated(l) => l.add_expansion(state),
+        })
+    }
+
     fn piece(&self) -> Option<Piece> {
         self.with(|this| match this.data {
             LayerKind::Known(l) => Some(l.piece),
@@ -237,11 +704,17 @@ impl<E: Evaluation> WithBump<E> {
         })
     }
 
-    fn select(&self, game_state: &GameState, speculate: bool, exploration: f64) -> SelectResult {
+    fn select(
+        &self,
+        game_state: &GameState,
+        speculate: bool,
+        policy: SelectionPolicy,
+        exploration: f64,
+    ) -> SelectResult {
         puffin::profile_function!();
         self.with(|this| match this.data {
-            LayerKind::Known(l) => l.select(game_state, exploration),
-            LayerKind::Speculated(l) if speculate => l.select(game_state, exploration),
+            LayerKind::Known(l) => l.select(game_state, policy, exploration),
+            LayerKind::Speculated(l) if speculate => l.select(game_state, policy, exploration),
             LayerKind::Speculated(_) => SelectResult::Failed,
         })
     }
@@ -254,27 +727,63 @@ impl<E: Evaluation> WithBump<E> {
         })
     }
 
-    fn despeculate(&mut self, piece: Piece) -> bool {
+    /// Makes the layer known, returning the updates for the layer above if it was speculated.
+    fn despeculate(&mut self, piece: Piece) -> Option<Vec<BackpropUpdate>> {
         puffin::profile_function!();
         self.with_mut(|this| {
             let old = match this.data {
-                LayerKind::Known(_) => return false,
+                LayerKind::Known(_) => return None,
                 LayerKind::Speculated(l) => std::mem::take(l),
             };
 
-            let layer = known::Layer {
+            let mut layer = known::Layer {
                 states: old.states.map_values(|node| known::Node {
                     parents: node.parents,
                     eval: node.eval,
                     children: node.children.map(|v| v.into_children(piece)),
                     expanding: node.expanding,
+                    visits: node.visits,
+                    expansions: node.expansions,
                 }),
                 piece,
+                memory: old.memory,
             };
 
+            let updates = layer.refresh();
             *this.data = LayerKind::Known(layer);
 
-            true
+            Some(updates)
+        })
+    }
+
+    fn index(&self, state: &GameState) -> u64 {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.states.index(state),
+            LayerKind::Speculated(l) => l.states.index(state),
+        })
+    }
+
+    fn memory(&self) -> usize {
+        self.with(|this| match this.data {
+            LayerKind::Known(l) => l.memory.load(atomic::Ordering::Relaxed),
+            LayerKind::Speculated(l) => l.memory.load(atomic::Ordering::Relaxed),
+        })
+    }
+
+    fn compact(
+        &self,
+        nodes: &[KeptNode],
+        keep: usize,
+        kept: &mut Vec<(GameState, Piece, Placement)>,
+    ) -> Self {
+        puffin::profile_function!();
+        WithBump::new(Herd::new(), |bump| {
+            self.with(|this| match this.data {
+                LayerKind::Known(l) => LayerKind::Known(l.compact(&bump.get(), nodes, keep, kept)),
+                LayerKind::Speculated(l) => {
+                    LayerKind::Speculated(l.compact(&bump.get(), nodes, keep, kept))
+                }
+            })
         })
     }
 
@@ -315,3 +824,458 @@ impl<E: Evaluation> Default for WithBump<E> {
         WithBump::new(Herd::new(), |_| LayerKind::Speculated(Default::default()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use enumset::EnumSet;
+
+    use super::*;
+    use crate::data::{Board, GarbageQueue, PieceLocation, Rotation, Spin};
+
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+    struct Score(i32);
+
+    impl std::ops::Add<i32> for Score {
+        type Output = Score;
+
+        fn add(self, reward: i32) -> Score {
+            Score(self.0 + reward)
+        }
+    }
+
+    impl Evaluation for Score {
+        type Reward = i32;
+
+        fn average(of: impl Iterator<Item = Option<Self>>) -> Self {
+            let scores: Vec<i32> = of.map(|s| s.map_or(-1000, |s| s.0)).collect();
+            Score(scores.iter().sum::<i32>() / scores.len() as i32)
+        }
+
+        fn value(self) -> f64 {
+            self.0 as f64
+        }
+    }
+
+    fn place(piece: Piece, x: i8, y: i8) -> Placement {
+        Placement {
+            location: PieceLocation {
+                piece,
+                rotation: Rotation::North,
+                x,
+                y,
+            },
+            spin: Spin::None,
+        }
+    }
+
+    fn placement(x: i8) -> Placement {
+        place(Piece::I, x, 0)
+    }
+
+    fn empty_state() -> GameState {
+        GameState {
+            board: Board::new(10),
+            bag: EnumSet::all(),
+            reserve: Piece::I,
+            back_to_back: 0,
+            combo: 0,
+            garbage: GarbageQueue::default(),
+            hold_locked: false,
+            hold_empty: false,
+            garbage_rows: 0,
+        }
+    }
+
+    /// Expands the position `policy` selects with `moves`, given as `(piece, placement, eval)`,
+    /// and returns the position.
+    fn expand(
+        dag: &Dag<Score>,
+        rules: &GameRules,
+        policy: SelectionPolicy,
+        moves: &[(Piece, Placement, i32)],
+    ) -> GameState {
+        let selection = dag.select(rules, true, policy, 1e9).unwrap();
+        let (state, _) = selection.state();
+        let mut children = EnumMap::<Piece, Vec<ChildData<Score>>>::default();
+        for &(piece, mv, eval) in moves {
+            let mut resulting_state = state;
+            resulting_state.advance(rules, piece, mv, state.held_by(mv, piece));
+            children[piece].push(ChildData {
+                resulting_state,
+                mv,
+                eval: Score(eval),
+                reward: 0,
+            });
+        }
+        selection.expand(children);
+        state
+    }
+
+    /// The moves from the root with their evaluations, best first.
+    fn root_children(dag: &Dag<Score>) -> Vec<(Placement, Score)> {
+        dag.top_layer
+            .kind
+            .children(&dag.root)
+            .into_iter()
+            .map(|(_, c)| (c.mv, c.cached_eval))
+            .collect()
+    }
+
+    /// Children ordered best first, from `(cached_eval, static_eval, visits)`.
+    fn children(list: &[(i32, i32, u32)]) -> Vec<Child<Score>> {
+        list.iter()
+            .enumerate()
+            .map(|(x, &(cached, fixed, visits))| Child {
+                mv: placement(x as i8 + 1),
+                reward: 0,
+                cached_eval: Score(cached),
+                static_eval: Score(fixed),
+                visits: AtomicU32::new(visits),
+            })
+            .collect()
+    }
+
+    fn picked(list: &[Child<Score>], policy: SelectionPolicy, exploitation: f64) -> usize {
+        let child = select_child(list, policy, exploitation);
+        list.iter().position(|c| std::ptr::eq(c, child)).unwrap()
+    }
+
+    #[test]
+    fn policy_parameters_are_clamped_when_read() {
+        let read = |json: &str| serde_json::from_str::<SelectionPolicy>(json).unwrap();
+        let puct = read(r#"{"type": "puct", "exploration": -1, "temperature": 0}"#);
+        assert!(matches!(
+            puct,
+            SelectionPolicy::Puct { exploration, temperature }
+                if exploration == 0.0 && temperature == MIN_TEMPERATURE
+        ));
+        let uct = read(r#"{"type": "uct", "exploration": -2}"#);
+        assert!(matches!(uct, SelectionPolicy::Uct { exploration } if exploration == 0.0));
+        let thompson = read(r#"{"type": "thompson", "spread": -0.5}"#);
+        assert!(matches!(thompson, SelectionPolicy::Thompson { spread } if spread == 0.0));
+
+        let nan = SelectionPolicy::from(RawSelectionPolicy::Puct {
+            exploration: f64::NAN,
+            temperature: f64::NAN,
+        });
+        assert!(matches!(
+            nan,
+            SelectionPolicy::Puct { exploration, temperature }
+                if exploration == 0.0 && temperature == MIN_TEMPERATURE
+        ));
+    }
+
+    #[test]
+    fn clamped_policies_pick_a_child() {
+        // equal evaluations and far apart static evaluations are the edge cases of the maths
+        let list = children(&[(3, 1000, 0), (3, -1000, 0), (3, 0, 2)]);
+        let policies = [
+            r#"{"type": "uct", "exploration": -1}"#,
+            r#"{"type": "puct", "exploration": -1, "temperature": 0}"#,
+            r#"{"type": "puct", "exploration": 1, "temperature": -5}"#,
+            r#"{"type": "thompson", "spread": -1}"#,
+        ];
+        for policy in policies {
+            let policy = serde_json::from_str(policy).unwrap();
+            assert!(picked(&list, policy, 1.0) < list.len());
+        }
+    }
+
+    #[test]
+    fn policies_pick_the_expected_child() {
+        // the best move has been looked at, the second has the best static evaluation but
+        // hasn't been visited, and the last is worst on both counts
+        let list = children(&[(10, 0, 5), (5, 10, 0), (0, 0, 5)]);
+
+        // with a huge exploitation every pick is the best move
+        assert_eq!(picked(&list, SelectionPolicy::Rank, 1e9), 0);
+        // unvisited moves come first
+        let uct = SelectionPolicy::Uct { exploration: 0.0 };
+        assert_eq!(picked(&list, uct, 1.0), 1);
+        // without exploration only the evaluation counts, and with it the prior does
+        let greedy = SelectionPolicy::Puct {
+            exploration: 0.0,
+            temperature: 1.0,
+        };
+        assert_eq!(picked(&list, greedy, 1.0), 0);
+        let exploring = SelectionPolicy::Puct {
+            exploration: 10.0,
+            temperature: 1.0,
+        };
+        assert_eq!(picked(&list, exploring, 1.0), 1);
+        // without noise Thompson sampling is greedy
+        let thompson = SelectionPolicy::Thompson { spread: 0.0 };
+        assert_eq!(picked(&list, thompson, 1.0), 0);
+    }
+
+    #[test]
+    fn compaction_keeps_the_best_children_and_the_chosen_line() {
+        let rules = GameRules::default();
+        let dag = &mut Dag::new(empty_state(), &[Piece::I, Piece::I, Piece::I]);
+        let evals = [10, 40, 30, 20, 0];
+        let moves: Vec<_> = (1..=5)
+            .zip(evals)
+            .map(|(x, eval)| (Piece::I, placement(x), eval))
+            .collect();
+        expand(dag, &rules, SelectionPolicy::Rank, &moves);
+        // the best move leads to an even better position
+        let best = expand(
+            dag,
+            &rules,
+            SelectionPolicy::Rank,
+            &[
+                (Piece::I, place(Piece::I, 1, 1), 50),
+                (Piece::I, place(Piece::I, 6, 1), 5),
+            ],
+        );
+
+        let children = root_children(dag);
+        assert_eq!(children[0], (placement(2), Score(50)));
+        let suggestion = dag.suggest();
+        let plan: Vec<_> = dag
+            .plan(&rules, 2)
+            .iter()
+            .map(|m| (m.mv, m.state))
+            .collect();
+        assert_eq!(plan.len(), 2);
+
+        dag.compact(&rules, 3);
+
+        assert_eq!(root_children(dag), children[..3]);
+        assert_eq!(dag.suggest(), suggestion);
+        let compacted: Vec<_> = dag
+            .plan(&rules, 2)
+            .iter()
+            .map(|m| (m.mv, m.state))
+            .collect();
+        assert_eq!(compacted, plan);
+        assert!(dag.contains(1, &best));
+        assert!(dag.contains(2, &plan[1].1));
+        let reached = |x| {
+            let mut state = empty_state();
+            state.advance(&rules, Piece::I, placement(x), false);
+            dag.contains(1, &state)
+        };
+        // the fourth best move and the worst were dropped
+        assert!(reached(3));
+        assert!(!reached(1));
+        assert!(!reached(5));
+
+        // the search carries on through the kept positions
+        expand(
+            dag,
+            &rules,
+            SelectionPolicy::Rank,
+            &[(Piece::I, place(Piece::I, 6, 2), 70)],
+        );
+        assert_eq!(root_children(dag)[0], (placement(2), Score(70)));
+    }
+
+    #[test]
+    fn updates_for_compacted_away_moves_are_ignored() {
+        // a list compacted down to its two best moves
+        let mut list = children(&[(10, 0, 0), (5, 0, 0), (0, 0, 0)]);
+        list.truncate(2);
+
+        assert!(!update_child(&mut list, placement(3), Score(100)));
+        let evals: Vec<_> = list.iter().map(|c| (c.mv, c.cached_eval)).collect();
+        assert_eq!(evals, [(placement(1), Score(10)), (placement(2), Score(5))]);
+
+        // a kept move that becomes the best is still moved to the front
+        assert!(update_child(&mut list, placement(2), Score(20)));
+        assert_eq!(list[0].mv, placement(2));
+        assert_eq!(list[1].mv, placement(1));
+    }
+
+    #[test]
+    fn despeculation_refreshes_the_evaluations() {
+        let rules = GameRules::default();
+        let dag = &mut Dag::new(empty_state(), &[Piece::I]);
+        expand(
+            dag,
+            &rules,
+            SelectionPolicy::Rank,
+            &[(Piece::I, placement(1), 10), (Piece::I, placement(5), 5)],
+        );
+        // the piece after the I isn't known yet, so both positions are worth the average of the
+        // best move for each piece left in the bag
+        let others = [Piece::O, Piece::S, Piece::Z, Piece::J, Piece::L];
+        let mut moves = vec![
+            (Piece::T, place(Piece::T, 4, 3), 100),
+            (Piece::T, place(Piece::T, 4, 6), 40),
+        ];
+        moves.extend(others.map(|p| (p, place(p, 4, 3), 0)));
+        let first = expand(dag, &rules, SelectionPolicy::Rank, &moves);
+        let mut moves = vec![(Piece::T, place(Piece::T, 4, 3), 20)];
+        moves.extend(others.map(|p| (p, place(p, 4, 3), 30)));
+        // the other move hasn't been visited yet
+        let uct = SelectionPolicy::Uct { exploration: 0.0 };
+        let second = expand(dag, &rules, uct, &moves);
+
+        let next_layer = &dag.top_layer.next_layer;
+        let stats = |state| next_layer.kind.stats(state).unwrap();
+        assert_eq!(stats(&first).eval, Score(100 / 6));
+        assert_eq!(stats(&second).eval, Score(170 / 6));
+        assert_eq!(
+            root_children(dag),
+            [
+                (placement(5), Score(170 / 6)),
+                (placement(1), Score(100 / 6))
+            ]
+        );
+        let visits = [stats(&first).visits, stats(&second).visits];
+
+        dag.add_piece(Piece::T);
+
+        let next_layer = &dag.top_layer.next_layer;
+        let stats = |state| next_layer.kind.stats(state).unwrap();
+        assert_eq!(stats(&first).eval, Score(100));
+        assert_eq!(stats(&second).eval, Score(20));
+        assert_eq!([stats(&first).visits, stats(&second).visits], visits);
+        assert_eq!(
+            root_children(dag),
+            [(placement(1), Score(100)), (placement(5), Score(20))]
+        );
+        let root = dag.top_layer.kind.stats(&dag.root).unwrap();
+        assert_eq!(root.eval, Score(100));
+
+        // only the moves for the piece that came are left
+        let children = next_layer.kind.children(&first);
+        assert!(children.iter().all(|&(piece, _)| piece == Piece::T));
+        assert_eq!(children[0].1.mv, place(Piece::T, 4, 3));
+        let plan = dag.plan(&rules, 2);
+        assert!(!plan[1].speculated);
+        assert_eq!(plan[1].mv, place(Piece::T, 4, 3));
+    }
+
+    #[test]
+    fn candidates_are_the_best_moves_with_their_lines() {
+        let rules = GameRules::default();
+        let dag = &Dag::new(empty_state(), &[Piece::I, Piece::I]);
+        let moves: Vec<_> = (1..=4)
+            .zip([10, 40, 30, 20])
+            .map(|(x, eval)| (Piece::I, placement(x), eval))
+            .collect();
+        expand(dag, &rules, SelectionPolicy::Rank, &moves);
+        expand(
+            dag,
+            &rules,
+            SelectionPolicy::Rank,
+            &[
+                (Piece::I, place(Piece::I, 6, 1), 50),
+                (Piece::I, place(Piece::I, 1, 1), 45),
+            ],
+        );
+
+        let candidates = dag.candidates(&rules, 3);
+        let ranked: Vec<_> = candidates.iter().map(|c| (c.mv, c.eval)).collect();
+        assert_eq!(
+            ranked,
+            [
+                (placement(2), Score(50)),
+                (placement(3), Score(30)),
+                (placement(4), Score(20)),
+            ]
+        );
+        // the line stops where the queue runs out
+        assert_eq!(candidates[0].pv, [place(Piece::I, 6, 1)]);
+        assert_eq!((candidates[0].visits, candidates[0].expansions), (1, 1));
+        assert!(candidates[1].pv.is_empty());
+        assert_eq!((candidates[1].visits, candidates[1].expansions), (0, 0));
+
+        assert_eq!(dag.candidates(&rules, 10).len(), 4);
+    }
+
+    #[test]
+    fn candidates_list_moves_using_the_reserve_once() {
+        let rules = GameRules::default();
+        // nothing is known about the queue, so the moves are listed for every next piece
+        let dag = &Dag::new(empty_state(), &[]);
+        let mut moves = vec![];
+        for piece in [Piece::T, Piece::O] {
+            moves.push((piece, placement(1), 10));
+            moves.push((piece, place(piece, 5, 0), 5));
+        }
+        expand(dag, &rules, SelectionPolicy::Rank, &moves);
+
+        let candidates: Vec<_> = dag
+            .candidates(&rules, 10)
+            .iter()
+            .map(|c| (c.mv, c.eval))
+            .collect();
+        assert_eq!(candidates[0], (placement(1), Score(10)));
+        assert_eq!(candidates.len(), 3);
+    }
+
+    /// A known I followed by an unknown piece, where the best I placement has been looked into
+    /// for every piece that can come after it.
+    fn speculated_line(rules: &GameRules) -> Dag<Score> {
+        let dag = Dag::new(empty_state(), &[Piece::I]);
+        expand(
+            &dag,
+            rules,
+            SelectionPolicy::Rank,
+            &[(Piece::I, placement(1), 10), (Piece::I, placement(5), 5)],
+        );
+        let moves = [
+            (Piece::T, 100),
+            (Piece::O, 0),
+            (Piece::S, 20),
+            (Piece::Z, 30),
+            (Piece::J, 40),
+            (Piece::L, 50),
+        ];
+        let mut moves: Vec<_> = moves.map(|(p, eval)| (p, place(p, 4, 3), eval)).into();
+        moves.push((Piece::T, place(Piece::T, 4, 6), 60));
+        expand(&dag, rules, SelectionPolicy::Rank, &moves);
+        dag
+    }
+
+    #[test]
+    fn plan_guesses_the_median_piece() {
+        let rules = GameRules::default();
+        let dag = speculated_line(&rules);
+
+        let plan: Vec<_> = dag
+            .plan(&rules, 5)
+            .iter()
+            .map(|m| (m.piece, m.speculated, m.mv, m.eval))
+            .collect();
+        // the line ends where the search hasn't looked any further
+        assert_eq!(
+            plan,
+            [
+                (Piece::I, false, placement(1), Score(240 / 6)),
+                (Piece::J, true, place(Piece::J, 4, 3), Score(40)),
+            ]
+        );
+        assert_eq!(dag.plan(&rules, 1).len(), 1);
+    }
+
+    #[test]
+    fn tree_export_is_bounded() {
+        let rules = GameRules::default();
+        let dag = speculated_line(&rules);
+
+        let tree = dag.tree(&rules, 1, 1);
+        assert_eq!(tree.eval, Score(240 / 6));
+        assert_eq!(tree.children.len(), 1);
+        assert_eq!(tree.children[0].mv, placement(1));
+        assert!(tree.children[0].node.children.is_empty());
+
+        let tree = dag.tree(&rules, 2, 1);
+        assert_eq!(tree.children.len(), 1);
+        let edges = &tree.children[0].node.children;
+        // the best move for each piece that can come
+        assert_eq!(edges.len(), 6);
+        assert!(edges.iter().all(|e| e.speculated));
+        let t = edges.iter().find(|e| e.piece == Piece::T).unwrap();
+        assert_eq!((t.mv, t.eval), (place(Piece::T, 4, 3), Score(100)));
+
+        let tree = dag.tree(&rules, 2, 2);
+        assert_eq!(tree.children.len(), 2);
+        assert_eq!(tree.children[0].node.children.len(), 7);
+        assert!(!tree.children[0].speculated);
+        assert_eq!(tree.children[0].visits, 1);
+    }
+}
@@ -1,19 +1,22 @@
-use std::sync::atomic::{self, AtomicBool};
+use std::mem::{size_of, size_of_val};
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicUsize};
 
 use bumpalo_herd::{Herd, Member};
 use enum_map::EnumMap;
-use rand::prelude::*;
 
 use crate::data::{GameState, Piece, Placement};
 use crate::map::StateMap;
 
 use super::{
-    update_child, BackpropUpdate, Child, ChildData, Evaluation, LayerCommon, SelectResult,
+    select_child, update_child, BackpropUpdate, Child, ChildData, Evaluation, KeptNode,
+    LayerCommon, NodeStats, SelectResult, SelectionPolicy,
 };
 
 pub(super) struct Layer<'bump, E: Evaluation> {
     pub states: StateMap<Node<'bump, E>>,
     pub piece: Piece,
+    /// Bytes used by the nodes and everything they point to.
+    pub memory: AtomicUsize,
 }
 
 pub(super) struct Node<'bump, E: Evaluation> {
@@ -21,6 +24,8 @@ pub(super) struct Node<'bump, E: Evaluation> {
     pub eval: E,
     pub children: Option<&'bump mut [Child<E>]>,
     pub expanding: AtomicBool,
+    pub visits: AtomicU32,
+    pub expansions: AtomicU32,
 }
 
 impl<'bump, E: Evaluation> Layer<'bump, E> {
@@ -30,6 +35,8 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             eval: E::default(),
             children: None,
             expanding: AtomicBool::new(false),
+            visits: AtomicU32::new(0),
+            expansions: AtomicU32::new(0),
         });
     }
 
@@ -48,12 +55,30 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         candidates.into_iter().map(|c| c.mv).collect()
     }
 
-    pub fn select(&self, game_state: &GameState, exploration: f64) -> SelectResult {
+    pub fn children(&self, state: &GameState) -> Vec<(Piece, Child<E>)> {
+        let node = match self.states.get(state) {
+            Some(node) => node,
+            None => return vec![],
+        };
+        node.children
+            .iter()
+            .flat_map(|children| children.iter())
+            .map(|c| (self.piece, c.clone()))
+            .collect()
+    }
+
+    pub fn select(
+        &self,
+        game_state: &GameState,
+        policy: SelectionPolicy,
+        exploration: f64,
+    ) -> SelectResult {
         puffin::profile_function!();
         let node = self
             .states
             .get(game_state)
             .expect("Link to non-existent node?");
+        node.visits.fetch_add(1, atomic::Ordering::Relaxed);
 
         let children = match &node.children {
             None => {
@@ -70,15 +95,28 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             return SelectResult::Failed;
         }
 
-        let s: f64 = thread_rng().gen();
-        let i = ((-s.ln() / exploration) % children.len() as f64) as usize;
-        SelectResult::Advance(self.piece, children[i].mv)
+        let child = select_child(children, policy, exploration);
+        SelectResult::Advance(self.piece, child.mv)
     }
 
     pub fn get_eval(&self, raw: u64) -> E {
         self.states.get_raw(raw).unwrap().eval
     }
 
+    pub fn stats(&self, state: &GameState) -> Option<NodeStats<E>> {
+        self.states.get(state).map(|node| NodeStats {
+            eval: node.eval,
+            visits: node.visits.load(atomic::Ordering::Relaxed),
+            expansions: node.expansions.load(atomic::Ordering::Relaxed),
+        })
+    }
+
+    pub fn add_expansion(&self, state: &GameState) {
+        if let Some(node) = self.states.get(state) {
+            node.expansions.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
     pub fn create_node(
         &self,
         bump: &Member<'bump>,
@@ -86,20 +124,26 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         parent: u64,
         speculation_piece: Piece,
     ) -> E {
-        let mut node = self
-            .states
-            .get_or_insert_with(&child.resulting_state, || Node {
+        let mut memory = 0;
+        let mut node = self.states.get_or_insert_with(&child.resulting_state, || {
+            memory += size_of::<(u64, Node<E>)>();
+            Node {
                 parents: &[],
                 eval: child.eval,
                 children: None,
                 expanding: AtomicBool::new(false),
-            });
+                visits: AtomicU32::new(0),
+                expansions: AtomicU32::new(0),
+            }
+        });
         node.parents = bump.alloc_slice_fill_with(node.parents.len() + 1, |i| {
             node.parents
                 .get(i)
                 .copied()
                 .unwrap_or((parent, child.mv, speculation_piece))
         });
+        memory += size_of_val(node.parents);
+        self.memory.fetch_add(memory, atomic::Ordering::Relaxed);
         node.eval
     }
 
@@ -124,10 +168,12 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
                 next_layer
                     .kind
                     .create_nodes(&children[self.piece], parent_index, self.piece);
-            for (child, eval) in children[self.piece].iter().zip(evals.into_iter()) {
+            for (child, eval) in children[self.piece].iter().zip(evals) {
                 childs.push(Child {
                     mv: child.mv,
                     cached_eval: eval + child.reward,
+                    static_eval: eval + child.reward,
+                    visits: AtomicU32::new(0),
                     reward: child.reward,
                 });
             }
@@ -136,7 +182,9 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         childs.sort_by(|a, b| a.cached_eval.cmp(&b.cached_eval).reverse());
 
         parent.eval = E::average(std::iter::once(childs.first().map(|c| c.cached_eval)));
-        parent.children = Some(herd.get().alloc_slice_copy(&childs));
+        parent.children = Some(herd.get().alloc_slice_clone(&childs));
+        self.memory
+            .fetch_add(size_of_val(&childs[..]), atomic::Ordering::Relaxed);
 
         let mut next = vec![];
 
@@ -152,6 +200,72 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         next
     }
 
+    /// Copies `nodes` into a new layer allocated from `bump`, linked to the given parents and
+    /// keeping only the `keep` best children of each node. The kept children are added to `kept`.
+    pub fn compact<'new>(
+        &self,
+        bump: &Member<'new>,
+        nodes: &[KeptNode],
+        keep: usize,
+        kept: &mut Vec<(GameState, Piece, Placement)>,
+    ) -> Layer<'new, E> {
+        let states = self.states.empty_like();
+        let mut memory = 0;
+        for (state, parents) in nodes {
+            let old = match self.states.get(state) {
+                Some(node) => node,
+                None => continue,
+            };
+            let children = old.children.as_ref().map(|children| {
+                let children = &children[..children.len().min(keep)];
+                kept.extend(children.iter().map(|c| (*state, self.piece, c.mv)));
+                memory += size_of_val(children);
+                bump.alloc_slice_clone(children)
+            });
+            memory += size_of::<(u64, Node<E>)>() + size_of_val(&parents[..]);
+            let _ = states.get_or_insert_with(state, || Node {
+                parents: bump.alloc_slice_copy(parents),
+                eval: old.eval,
+                children,
+                expanding: AtomicBool::new(old.expanding.load(atomic::Ordering::Relaxed)),
+                visits: AtomicU32::new(old.visits.load(atomic::Ordering::Relaxed)),
+                expansions: AtomicU32::new(old.expansions.load(atomic::Ordering::Relaxed)),
+            });
+        }
+        Layer {
+            states,
+            piece: self.piece,
+            memory: AtomicUsize::new(memory),
+        }
+    }
+
+    /// Recomputes the evaluation of every expanded node from its best child, returning the
+    /// updates for the parents of the nodes whose evaluation changed. Needed after the layer is
+    /// despeculated, as the evaluations were averaged over every piece that could have come.
+    pub fn refresh(&mut self) -> Vec<BackpropUpdate> {
+        puffin::profile_function!();
+        let mut updates = vec![];
+        self.states.for_each_mut(|raw, node| {
+            let children = match &node.children {
+                Some(children) => children,
+                None => return,
+            };
+            let eval = E::average(std::iter::once(children.first().map(|c| c.cached_eval)));
+            if node.eval != eval {
+                node.eval = eval;
+                for &(parent, mv, speculation_piece) in node.parents {
+                    updates.push(BackpropUpdate {
+                        parent,
+                        mv,
+                        speculation_piece,
+                        child: raw,
+                    });
+                }
+            }
+        });
+        updates
+    }
+
     pub fn backprop(
         &self,
         to_update: Vec<BackpropUpdate>,
@@ -1,5 +1,6 @@
+use std::mem::{size_of, size_of_val};
 use std::ops::{Index, IndexMut};
-use std::sync::atomic::{self, AtomicBool};
+use std::sync::atomic::{self, AtomicBool, AtomicU32, AtomicUsize};
 
 use bumpalo_herd::{Herd, Member};
 use enum_map::EnumMap;
@@ -10,12 +11,15 @@ use crate::data::{GameState, Piece, Placement};
 use crate::map::StateMap;
 
 use super::{
-    update_child, BackpropUpdate, Child, ChildData, Evaluation, LayerCommon, SelectResult,
+    select_child, update_child, BackpropUpdate, Child, ChildData, Evaluation, KeptNode,
+    LayerCommon, NodeStats, SelectResult, SelectionPolicy,
 };
 
 #[derive(Default)]
 pub(super) struct Layer<'bump, E: Evaluation> {
     pub states: StateMap<Node<'bump, E>>,
+    /// Bytes used by the nodes and everything they point to.
+    pub memory: AtomicUsize,
 }
 
 pub(super) struct Node<'bump, E: Evaluation> {
@@ -23,6 +27,8 @@ pub(super) struct Node<'bump, E: Evaluation> {
     pub eval: E,
     pub children: Option<PackedChildren<'bump, E>>,
     pub expanding: AtomicBool,
+    pub visits: AtomicU32,
+    pub expansions: AtomicU32,
     // we need this info while backpropagating, but we don't have access to the game state then
     bag: EnumSet<Piece>,
 }
@@ -34,6 +40,8 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             eval: E::default(),
             children: None,
             expanding: AtomicBool::new(false),
+            visits: AtomicU32::new(0),
+            expansions: AtomicU32::new(0),
             bag: root.bag,
         });
     }
@@ -55,12 +63,34 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         candidates.into_iter().map(|c| c.mv).collect()
     }
 
-    pub fn select(&self, game_state: &GameState, exploration: f64) -> SelectResult {
+    pub fn children(&self, state: &GameState) -> Vec<(Piece, Child<E>)> {
+        let node = match self.states.get(state) {
+            Some(node) => node,
+            None => return vec![],
+        };
+        let children = match &node.children {
+            Some(children) => children,
+            None => return vec![],
+        };
+        state
+            .bag
+            .iter()
+            .flat_map(|piece| children[piece].iter().map(move |c| (piece, c.clone())))
+            .collect()
+    }
+
+    pub fn select(
+        &self,
+        game_state: &GameState,
+        policy: SelectionPolicy,
+        exploration: f64,
+    ) -> SelectResult {
         puffin::profile_function!();
         let node = self
             .states
             .get(game_state)
             .expect("Link to non-existent node?");
+        node.visits.fetch_add(1, atomic::Ordering::Relaxed);
 
         let children = match &node.children {
             None => {
@@ -83,15 +113,28 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
             return SelectResult::Failed;
         }
 
-        let s: f64 = thread_rng().gen();
-        let i = ((-s.ln() / exploration) % children[next].len() as f64) as usize;
-        SelectResult::Advance(next, children[next][i].mv)
+        let child = select_child(&children[next], policy, exploration);
+        SelectResult::Advance(next, child.mv)
     }
 
     pub fn get_eval(&self, raw: u64) -> E {
         self.states.get_raw(raw).unwrap().eval
     }
 
+    pub fn stats(&self, state: &GameState) -> Option<NodeStats<E>> {
+        self.states.get(state).map(|node| NodeStats {
+            eval: node.eval,
+            visits: node.visits.load(atomic::Ordering::Relaxed),
+            expansions: node.expansions.load(atomic::Ordering::Relaxed),
+        })
+    }
+
+    pub fn add_expansion(&self, state: &GameState) {
+        if let Some(node) = self.states.get(state) {
+            node.expansions.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
     pub fn create_node(
         &self,
         bump: &Member<'bump>,
@@ -99,21 +142,27 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         parent: u64,
         speculation_piece: Piece,
     ) -> E {
-        let mut node = self
-            .states
-            .get_or_insert_with(&child.resulting_state, || Node {
+        let mut memory = 0;
+        let mut node = self.states.get_or_insert_with(&child.resulting_state, || {
+            memory += size_of::<(u64, Node<E>)>();
+            Node {
                 parents: &[],
                 eval: child.eval,
                 children: None,
                 expanding: AtomicBool::new(false),
+                visits: AtomicU32::new(0),
+                expansions: AtomicU32::new(0),
                 bag: child.resulting_state.bag,
-            });
+            }
+        });
         node.parents = bump.alloc_slice_fill_with(node.parents.len() + 1, |i| {
             node.parents
                 .get(i)
                 .copied()
                 .unwrap_or((parent, child.mv, speculation_piece))
         });
+        memory += size_of_val(node.parents);
+        self.memory.fetch_add(memory, atomic::Ordering::Relaxed);
         node.eval
     }
 
@@ -141,10 +190,12 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
                     parent_index,
                     speculation_piece,
                 );
-                for (child, eval) in children[speculation_piece].iter().zip(evals.into_iter()) {
+                for (child, eval) in children[speculation_piece].iter().zip(evals) {
                     childs_data.push(Child {
                         mv: child.mv,
                         cached_eval: eval + child.reward,
+                        static_eval: eval + child.reward,
+                        visits: AtomicU32::new(0),
                         reward: child.reward,
                     });
                 }
@@ -153,9 +204,11 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         }
 
         let mut children = PackedChildren {
-            data: herd.get().alloc_slice_copy(&childs_data),
+            data: herd.get().alloc_slice_clone(&childs_data),
             start_indices: childs_indices,
         };
+        self.memory
+            .fetch_add(size_of_val(&childs_data[..]), atomic::Ordering::Relaxed);
 
         for p in EnumSet::all() {
             children[p].sort_by(|a, b| a.cached_eval.cmp(&b.cached_eval).reverse());
@@ -184,6 +237,59 @@ impl<'bump, E: Evaluation> Layer<'bump, E> {
         next
     }
 
+    /// Copies `nodes` into a new layer allocated from `bump`, linked to the given parents and
+    /// keeping only the `keep` best children of each node for each possible next piece. The
+    /// kept children are added to `kept`.
+    pub fn compact<'new>(
+        &self,
+        bump: &Member<'new>,
+        nodes: &[KeptNode],
+        keep: usize,
+        kept: &mut Vec<(GameState, Piece, Placement)>,
+    ) -> Layer<'new, E> {
+        let states = self.states.empty_like();
+        let mut memory = 0;
+        for (state, parents) in nodes {
+            let old = match self.states.get(state) {
+                Some(node) => node,
+                None => continue,
+            };
+            let children = old.children.as_ref().map(|children| {
+                let mut data = vec![];
+                let mut start_indices = [0; 8];
+                for piece in EnumSet::all() {
+                    // children for pieces that can't come next are never looked at
+                    let list = match old.bag.contains(piece) {
+                        true => &children[piece][..children[piece].len().min(keep)],
+                        false => &[],
+                    };
+                    kept.extend(list.iter().map(|c| (*state, piece, c.mv)));
+                    data.extend_from_slice(list);
+                    start_indices[piece as usize + 1] = data.len() as u16;
+                }
+                memory += size_of_val(&data[..]);
+                PackedChildren {
+                    data: bump.alloc_slice_clone(&data),
+                    start_indices,
+                }
+            });
+            memory += size_of::<(u64, Node<E>)>() + size_of_val(&parents[..]);
+            let _ = states.get_or_insert_with(state, || Node {
+                parents: bump.alloc_slice_copy(parents),
+                eval: old.eval,
+                children,
+                expanding: AtomicBool::new(old.expanding.load(atomic::Ordering::Relaxed)),
+                visits: AtomicU32::new(old.visits.load(atomic::Ordering::Relaxed)),
+                expansions: AtomicU32::new(old.expansions.load(atomic::Ordering::Relaxed)),
+                bag: old.bag,
+            });
+        }
+        Layer {
+            states,
+            memory: AtomicUsize::new(memory),
+        }
+    }
+
     pub fn backprop(
         &self,
         to_update: Vec<BackpropUpdate>,
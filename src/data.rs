@@ -2,10 +2,17 @@ use enum_map::Enum;
 use enumset::{EnumSet, EnumSetType};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
-#[serde(from = "Vec<[Option<char>; 10]>")]
+use crate::rules::{GameRules, HoldMode};
+
+/// Widest board supported.
+pub const MAX_WIDTH: usize = 12;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(from = "Vec<Vec<Option<char>>>", into = "Vec<Vec<Option<char>>>")]
 pub struct Board {
-    pub cols: [u64; 10],
+    /// Columns past `width` are always empty.
+    cols: [u64; MAX_WIDTH],
+    width: u8,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -13,8 +20,38 @@ pub struct GameState {
     pub board: Board,
     pub bag: EnumSet<Piece>,
     pub reserve: Piece,
-    pub back_to_back: bool,
+    /// Number of consecutive hard line clears, capped at the longest chain the attack rules
+    /// distinguish. Nonzero means the next hard clear is back-to-back.
+    pub back_to_back: u8,
     pub combo: u8,
+    pub garbage: GarbageQueue,
+    /// Whether the next placement can't use hold because the last one did, under
+    /// [`HoldMode::Restricted`].
+    pub hold_locked: bool,
+    /// Whether nothing has been held yet, in which case `reserve` is the piece in play and
+    /// holding it brings out the next piece instead.
+    pub hold_empty: bool,
+    /// Number of rows at the bottom of the board that are garbage.
+    pub garbage_rows: u8,
+}
+
+/// Incoming garbage that has not landed on the board yet, in the order it will arrive.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct GarbageQueue {
+    entries: [Garbage; MAX_GARBAGE_ENTRIES],
+    len: u8,
+}
+
+const MAX_GARBAGE_ENTRIES: usize = 8;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Garbage {
+    pub lines: u8,
+    /// Number of placements before this garbage is ready to land.
+    pub delay: u8,
+    /// Column of the hole. When unknown or off the board, the hole is assumed to be in the lowest
+    /// column.
+    pub column: Option<u8>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
@@ -40,9 +77,14 @@ pub struct PlacementInfo {
     pub combo: u32,
     pub back_to_back: bool,
     pub perfect_clear: bool,
+    pub attack: u32,
+    pub garbage_cancelled: u32,
+    pub garbage_received: u32,
+    /// Number of garbage rows among the lines cleared.
+    pub garbage_cleared: u32,
 }
 
-#[allow(clippy::derive_hash_xor_eq)]
+#[allow(clippy::derived_hash_with_manual_eq)]
 #[derive(EnumSetType, Enum, Debug, Hash, Serialize, Deserialize)]
 pub enum Piece {
     I,
@@ -247,16 +289,60 @@ impl PieceLocation {
     }
 }
 
+impl Default for Board {
+    fn default() -> Self {
+        Board::new(10)
+    }
+}
+
 impl Board {
+    /// An empty board `width` columns wide, clamped to the widths pieces fit in.
+    pub fn new(width: u8) -> Self {
+        Board {
+            cols: [0; MAX_WIDTH],
+            width: width.clamp(4, MAX_WIDTH as u8),
+        }
+    }
+
+    pub fn from_cols(cols: &[u64]) -> Self {
+        let mut board = Board::new(cols.len() as u8);
+        for (c, &col) in board.cols.iter_mut().zip(cols) {
+            *c = col;
+        }
+        board
+    }
+
+    pub fn width(&self) -> i8 {
+        self.width as i8
+    }
+
+    pub fn cols(&self) -> &[u64] {
+        &self.cols[..self.width as usize]
+    }
+
+    /// The columns of the board, as bitboards with row 0 in the lowest bit. Rows from 40 up must
+    /// be left empty.
+    pub fn cols_mut(&mut self) -> &mut [u64] {
+        &mut self.cols[..self.width as usize]
+    }
+
+    /// Changes the width of the board, dropping any cells in removed columns.
+    pub fn set_width(&mut self, width: u8) {
+        self.width = width.clamp(4, MAX_WIDTH as u8);
+        for c in &mut self.cols[self.width as usize..] {
+            *c = 0;
+        }
+    }
+
     pub const fn occupied(&self, (x, y): (i8, i8)) -> bool {
-        if x < 0 || x >= 10 || y < 0 || y >= 40 {
+        if x < 0 || x >= self.width as i8 || y < 0 || y >= 40 {
             return true;
         }
         self.cols[x as usize] & 1 << y != 0
     }
 
     pub fn distance_to_ground(&self, x: i8, y: i8) -> i8 {
-        debug_assert!((0..10).contains(&x));
+        debug_assert!((0..self.width()).contains(&x));
         debug_assert!((0..40).contains(&y));
         if y == 0 {
             return 0;
@@ -266,25 +352,145 @@ impl Board {
 
     pub fn place(&mut self, piece: PieceLocation) {
         for &(x, y) in &piece.cells() {
-            debug_assert!((0..10).contains(&x));
+            debug_assert!((0..self.width()).contains(&x));
             debug_assert!((0..40).contains(&y));
             self.cols[x as usize] |= 1 << y;
         }
     }
 
     pub fn line_clears(&self) -> u64 {
-        self.cols.iter().fold(!0, |a, b| a & b)
+        self.cols().iter().fold(!0, |a, b| a & b)
     }
 
     pub fn remove_lines(&mut self, lines: u64) {
-        for c in &mut self.cols {
+        for c in &mut self.cols[..self.width as usize] {
             clear_lines(c, lines);
         }
     }
+
+    pub fn insert_garbage(&mut self, lines: u32, column: Option<u8>) {
+        let width = self.width as usize;
+        let column = column
+            .map(usize::from)
+            .filter(|&x| x < width)
+            .unwrap_or_else(|| {
+                (0..width)
+                    .min_by_key(|&x| 64 - self.cols[x].leading_zeros())
+                    .unwrap()
+            });
+        let lines = lines.min(40);
+        let filled = (1 << lines) - 1;
+        for (x, c) in self.cols[..width].iter_mut().enumerate() {
+            // cells pushed past the top of the board are gone
+            *c = (*c << lines) & ((1 << 40) - 1);
+            if x != column {
+                *c |= filled;
+            }
+        }
+    }
+}
+
+impl GarbageQueue {
+    pub fn push(&mut self, garbage: Garbage) {
+        if garbage.lines == 0 {
+            return;
+        }
+        if (self.len as usize) < MAX_GARBAGE_ENTRIES {
+            self.entries[self.len as usize] = garbage;
+            self.len += 1;
+        } else {
+            let last = &mut self.entries[MAX_GARBAGE_ENTRIES - 1];
+            last.lines = last.lines.saturating_add(garbage.lines);
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Garbage> {
+        self.entries[..self.len as usize].iter()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn total_lines(&self) -> u32 {
+        self.iter().map(|g| g.lines as u32).sum()
+    }
+
+    /// Cancels up to `lines` lines of garbage, oldest first. Returns the number of lines cancelled.
+    pub fn cancel(&mut self, mut lines: u32) -> u32 {
+        let mut cancelled = 0;
+        while lines > 0 && !self.is_empty() {
+            let first = &mut self.entries[0];
+            let amount = lines.min(first.lines as u32);
+            first.lines -= amount as u8;
+            lines -= amount;
+            cancelled += amount;
+            if first.lines == 0 {
+                self.remove_first();
+            }
+        }
+        cancelled
+    }
+
+    /// Removes every entry that is ready to land, passing it to `f`.
+    pub fn take_ready(&mut self, mut f: impl FnMut(Garbage)) {
+        while !self.is_empty() && self.entries[0].delay == 0 {
+            f(self.entries[0]);
+            self.remove_first();
+        }
+    }
+
+    pub fn tick(&mut self) {
+        for g in &mut self.entries[..self.len as usize] {
+            g.delay = g.delay.saturating_sub(1);
+        }
+    }
+
+    fn remove_first(&mut self) {
+        self.entries.copy_within(1..self.len as usize, 0);
+        self.len -= 1;
+        self.entries[self.len as usize] = Garbage::default();
+    }
+}
+
+impl FromIterator<Garbage> for GarbageQueue {
+    fn from_iter<T: IntoIterator<Item = Garbage>>(iter: T) -> Self {
+        let mut queue = GarbageQueue::default();
+        for garbage in iter {
+            queue.push(garbage);
+        }
+        queue
+    }
 }
 
 impl GameState {
-    pub fn advance(&mut self, next: Piece, placement: Placement) -> PlacementInfo {
+    /// Whether the next placement may use the reserve piece.
+    pub fn can_hold(&self, rules: &GameRules) -> bool {
+        match rules.hold {
+            HoldMode::Enabled => true,
+            HoldMode::Disabled => false,
+            HoldMode::Restricted => !self.hold_locked,
+        }
+    }
+
+    /// Whether placing `placement` with `next` as the piece drawn from the queue needs a hold.
+    /// While the hold slot is empty that's playing `next`, and otherwise playing the reserve.
+    pub fn held_by(&self, placement: Placement, next: Piece) -> bool {
+        match self.hold_empty {
+            true => placement.location.piece != self.reserve,
+            false => placement.location.piece != next,
+        }
+    }
+
+    /// Places `placement` with `next` as the piece drawn from the queue. `held` is whether the
+    /// player pressed hold for it, see [`GameState::held_by`].
+    pub fn advance(
+        &mut self,
+        rules: &GameRules,
+        next: Piece,
+        placement: Placement,
+        held: bool,
+    ) -> PlacementInfo {
         self.bag.remove(next);
         if self.bag.is_empty() {
             self.bag = EnumSet::all();
@@ -292,24 +498,49 @@ impl GameState {
         if placement.location.piece != next {
             self.reserve = next;
         }
+        self.hold_locked = held && rules.hold == HoldMode::Restricted;
+        self.hold_empty &= !held;
         self.board.place(placement.location);
         let cleared_mask = self.board.line_clears();
-        let mut back_to_back = false;
+        let mut info = PlacementInfo {
+            placement,
+            lines_cleared: cleared_mask.count_ones(),
+            combo: 0,
+            back_to_back: false,
+            perfect_clear: false,
+            attack: 0,
+            garbage_cancelled: 0,
+            garbage_received: 0,
+            garbage_cleared: 0,
+        };
         if cleared_mask != 0 {
+            let table = rules.attack.table();
+            info.garbage_cleared = (cleared_mask & ((1 << self.garbage_rows) - 1)).count_ones();
+            self.garbage_rows -= info.garbage_cleared as u8;
             self.board.remove_lines(cleared_mask);
-            let hard = cleared_mask.count_ones() == 4 || !matches!(placement.spin, Spin::None);
-            back_to_back = hard && self.back_to_back;
-            self.back_to_back = hard;
+            self.combo = self.combo.saturating_add(1);
+            let chain = self.back_to_back;
+            let hard = info.lines_cleared == 4 || !matches!(placement.spin, Spin::None);
+            info.back_to_back = hard && chain > 0;
+            self.back_to_back = match hard {
+                true => (chain + 1).min(table.max_back_to_back_chain()),
+                false => 0,
+            };
+            info.combo = self.combo as u32;
+            info.perfect_clear = self.board.cols().iter().all(|&c| c == 0);
+            info.attack = table.attack(&info, chain);
+            info.garbage_cancelled = self.garbage.cancel(info.attack);
         } else {
             self.combo = 0;
+            let board = &mut self.board;
+            self.garbage.take_ready(|g| {
+                board.insert_garbage(g.lines as u32, g.column);
+                info.garbage_received += g.lines as u32;
+            });
+            self.garbage_rows = (self.garbage_rows as u32 + info.garbage_received).min(40) as u8;
         }
-        PlacementInfo {
-            placement,
-            lines_cleared: cleared_mask.count_ones(),
-            combo: self.combo as u32,
-            back_to_back,
-            perfect_clear: self.board.cols.iter().all(|&c| c == 0),
-        }
+        self.garbage.tick();
+        info
     }
 }
 
@@ -331,3 +562,122 @@ fn clear_lines(col: &mut u64, mut lines: u64) {
         lines >>= 1;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn garbage(lines: u8, delay: u8) -> Garbage {
+        Garbage {
+            lines,
+            delay,
+            column: Some(0),
+        }
+    }
+
+    fn empty_state() -> GameState {
+        GameState {
+            board: Board::new(10),
+            bag: EnumSet::all(),
+            reserve: Piece::I,
+            back_to_back: 0,
+            combo: 0,
+            garbage: GarbageQueue::default(),
+            hold_locked: false,
+            hold_empty: false,
+            garbage_rows: 0,
+        }
+    }
+
+    #[test]
+    fn garbage_is_cancelled_oldest_first() {
+        let mut queue: GarbageQueue = [garbage(2, 0), garbage(3, 1)].into_iter().collect();
+        assert_eq!(queue.cancel(3), 3);
+        assert_eq!(queue.iter().copied().collect::<Vec<_>>(), [garbage(2, 1)]);
+        assert_eq!(queue.cancel(5), 2);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn garbage_lands_once_ready() {
+        let mut queue: GarbageQueue = [garbage(1, 0), garbage(2, 1), garbage(3, 0)]
+            .into_iter()
+            .collect();
+        let mut landed = vec![];
+        queue.take_ready(|g| landed.push(g));
+        // garbage waits on the entries before it
+        assert_eq!(landed, [garbage(1, 0)]);
+
+        queue.tick();
+        queue.take_ready(|g| landed.push(g));
+        assert_eq!(landed, [garbage(1, 0), garbage(2, 0), garbage(3, 0)]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn garbage_past_the_last_entry_joins_it() {
+        let mut queue: GarbageQueue = (0..MAX_GARBAGE_ENTRIES + 2)
+            .map(|_| garbage(1, 0))
+            .collect();
+        assert_eq!(queue.iter().count(), MAX_GARBAGE_ENTRIES);
+        assert_eq!(queue.iter().last(), Some(&garbage(3, 0)));
+        assert_eq!(queue.total_lines(), MAX_GARBAGE_ENTRIES as u32 + 2);
+
+        queue.push(garbage(250, 0));
+        assert_eq!(queue.iter().last(), Some(&garbage(253, 0)));
+        queue.push(garbage(250, 0));
+        assert_eq!(queue.iter().last(), Some(&garbage(255, 0)));
+    }
+
+    #[test]
+    fn garbage_pushes_the_board_up() {
+        let mut board = Board::new(10);
+        board.cols_mut()[3] = 1 << 39 | 1;
+        board.insert_garbage(2, Some(5));
+        for (x, &col) in board.cols().iter().enumerate() {
+            let garbage = if x == 5 { 0 } else { 0b11 };
+            let stack = if x == 3 { 0b100 } else { 0 };
+            assert_eq!(col, garbage | stack, "column {x}");
+        }
+    }
+
+    #[test]
+    fn garbage_lands_when_nothing_is_cleared() {
+        let rules = GameRules::default();
+        let mut state = empty_state();
+        state.garbage = [garbage(2, 0), garbage(1, 1)].into_iter().collect();
+        let mv = Placement {
+            location: PieceLocation {
+                piece: Piece::I,
+                rotation: Rotation::North,
+                x: 1,
+                y: 0,
+            },
+            spin: Spin::None,
+        };
+
+        let info = state.advance(&rules, Piece::I, mv, false);
+        assert_eq!(info.garbage_received, 2);
+        assert_eq!(state.garbage_rows, 2);
+        // the hole is under the piece in column 0
+        assert_eq!(state.board.cols()[0], 0b100);
+        assert_eq!(state.board.cols()[1], 0b111);
+        assert_eq!(
+            state.garbage.iter().copied().collect::<Vec<_>>(),
+            [garbage(1, 0)]
+        );
+
+        let mv = Placement {
+            location: PieceLocation {
+                x: 7,
+                y: 2,
+                ..mv.location
+            },
+            ..mv
+        };
+        let info = state.advance(&rules, Piece::I, mv, false);
+        assert_eq!(info.garbage_received, 1);
+        assert_eq!(state.garbage_rows, 3);
+        assert!(state.garbage.is_empty());
+    }
+}
@@ -7,54 +7,88 @@ use futures::prelude::*;
 use sync::BotSyncronizerWASM;
 use tbp::Randomizer;
 
-use crate::bot::Bot;
-use crate::data::GameState;
-use crate::sync::BotSyncronizer;
-use crate::tbp::{BotMessage, FrontendMessage};
+use crate::bot::{Bot, TreeNode};
+use crate::data::{Board, GameState, Piece};
+use crate::rules::HoldMode;
+use crate::session::{Session, WorkerPool};
+use crate::tbp::{BotMessage, FrontendMessage, TreeFormat};
 
-mod bot;
+pub mod bot;
 mod dag;
-mod tbp;
+pub mod session;
+pub mod tbp;
 #[macro_use]
 pub mod data;
 mod map;
 pub mod movegen;
+pub mod rules;
 mod sync;
 
-use wasm_bindgen::prelude::*;
 use futures::channel::mpsc;
-use futures::StreamExt;
+use futures::{future, stream, StreamExt};
+use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
 pub struct Service {
     sender: mpsc::UnboundedSender<String>,
 }
 
+impl Default for Service {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[wasm_bindgen]
 impl Service {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Service {
-        let (sender, mut receiver) = mpsc::unbounded::<String>();
+        let (sender, receiver) = mpsc::unbounded::<String>();
+        let (budget_spent, budget_events) = mpsc::unbounded();
 
-        let bot = Arc::new(BotSyncronizerWASM::new());
+        let bot = Arc::new(BotSyncronizerWASM::new(budget_spent));
         let mut waiting_on_first_piece = None;
         let worker = bot.clone();
         wasm_bindgen_futures::spawn_local(async move {
             worker.work_loop().await;
         });
 
-        log(&serde_json::to_string(&BotMessage::Info {
-            name: "Cold Clear 2",
-            version: concat!(env!("CARGO_PKG_VERSION"), " ", env!("GIT_HASH")),
-            author: "MinusKelvin",
-            features: &[],
-        }).unwrap());
+        log(&serde_json::to_string(&BotMessage::info()).unwrap());
         wasm_bindgen_futures::spawn_local(async move {
-            
-
-            let config = Arc::new(BotConfig::default());
-            while let Some(raw) = receiver.next().await {
-                let msg = serde_json::from_str::<FrontendMessage>(&raw).unwrap();
+            let mut config = Arc::new(BotConfig::default());
+            let mut events = stream::select(
+                receiver.filter_map(|raw| {
+                    let msg = match serde_json::from_str(&raw) {
+                        Ok(msg) => Some(Event::Message(msg)),
+                        Err(e) => {
+                            // like the native version, a bad message is skipped rather than
+                            // taking the bot down
+                            log_error(&format!("ignoring malformed message: {e}"));
+                            None
+                        }
+                    };
+                    future::ready(msg)
+                }),
+                budget_events.map(|()| Event::BudgetSpent),
+            );
+            while let Some(event) = events.next().await {
+                let msg = match event {
+                    Event::Message(msg) => msg,
+                    Event::BudgetSpent => {
+                        if let Some((moves, move_info)) = bot.take_auto_suggestion().await {
+                            log(&serde_json::to_string(&BotMessage::Suggestion {
+                                moves,
+                                move_info,
+                            })
+                            .unwrap());
+                        }
+                        continue;
+                    }
+                    Event::Reply(reply) => {
+                        log(&serde_json::to_string(&reply).unwrap());
+                        continue;
+                    }
+                };
                 match msg {
                     FrontendMessage::Start(start) => {
                         if start.hold.is_none() && start.queue.is_empty() {
@@ -63,17 +97,56 @@ impl Service {
                             bot.start(create_bot(start, config.clone())).await;
                         }
                     }
+                    FrontendMessage::Resync(start) => {
+                        if start.hold.is_none() && start.queue.is_empty() {
+                            waiting_on_first_piece = Some(start);
+                            continue;
+                        }
+                        waiting_on_first_piece = None;
+                        let (position, queue) = start_position(start.clone(), &config);
+                        match bot.resync(position, &queue).await {
+                            Some(Some(recovery)) => {
+                                log(&serde_json::to_string(&BotMessage::Recovered { recovery })
+                                    .unwrap());
+                            }
+                            Some(None) => {}
+                            None => bot.start(create_bot(start, config.clone())).await,
+                        }
+                    }
                     FrontendMessage::Stop => {
                         bot.stop().await;
                         waiting_on_first_piece = None;
                     }
+                    FrontendMessage::Pause => bot.pause().await,
+                    FrontendMessage::Resume => bot.resume().await,
                     FrontendMessage::Suggest => {
                         if let Some((moves, move_info)) = bot.suggest().await {
-                            log(&serde_json::to_string(&BotMessage::Suggestion { moves, move_info }).unwrap());
+                            log(&serde_json::to_string(&BotMessage::Suggestion {
+                                moves,
+                                move_info,
+                            })
+                            .unwrap());
+                        }
+                    }
+                    FrontendMessage::Plan { depth } => {
+                        if let Some(moves) = bot.plan(depth).await {
+                            log(&serde_json::to_string(&BotMessage::Plan { moves }).unwrap());
+                        }
+                    }
+                    FrontendMessage::Tree {
+                        depth,
+                        breadth,
+                        format,
+                    } => {
+                        if let Some(tree) = bot.tree(depth, breadth).await {
+                            log(&serde_json::to_string(&tree_message(tree, format)).unwrap());
                         }
                     }
                     FrontendMessage::Play { mv } => {
-                        bot.advance(mv).await;
+                        if let Some(recovery) = bot.advance(mv).await {
+                            log(&serde_json::to_string(&BotMessage::Recovered { recovery })
+                                .unwrap());
+                        }
                     }
                     FrontendMessage::NewPiece { piece } => {
                         if let Some(mut start) = waiting_on_first_piece.take() {
@@ -89,13 +162,31 @@ impl Service {
                             bot.new_piece(piece).await;
                         }
                     }
-                    FrontendMessage::Rules => {
+                    FrontendMessage::Garbage { garbage } => {
+                        if let Some(start) = &mut waiting_on_first_piece {
+                            start.garbage = garbage;
+                        } else {
+                            bot.set_garbage(garbage.into_iter().map(Into::into).collect())
+                                .await;
+                        }
+                    }
+                    FrontendMessage::Rules(rules) => {
+                        let mut new_config = (*config).clone();
+                        rules.apply(&mut new_config.rules);
+                        config = Arc::new(new_config);
                         log(&serde_json::to_string(&BotMessage::Ready).unwrap());
                     }
+                    FrontendMessage::Limits(limits) => {
+                        let mut new_config = (*config).clone();
+                        new_config.limits = limits;
+                        config = Arc::new(new_config);
+                        bot.set_limits(limits).await;
+                    }
                     FrontendMessage::Quit => break,
                     FrontendMessage::Unknown => {}
                 }
             }
+            bot.shutdown().await;
         });
 
         Service { sender }
@@ -111,80 +202,91 @@ impl Service {
 #[wasm_bindgen]
 extern "C" {
     fn log(s: &str);
+
+    #[wasm_bindgen(js_namespace = console, js_name = error)]
+    fn log_error(s: &str);
 }
+enum Event {
+    Message(FrontendMessage),
+    /// The search budget for the current move ran out.
+    BudgetSpent,
+    Reply(BotMessage),
+}
+
+/// Runs the bot until `incoming` ends or the frontend quits.
 pub async fn run(
-    mut incoming: impl Stream<Item = FrontendMessage> + Unpin,
+    incoming: impl Stream<Item = FrontendMessage> + Unpin,
     mut outgoing: impl Sink<BotMessage, Error = Infallible> + Unpin,
     config: Arc<BotConfig>,
 ) {
-    outgoing
-        .send(BotMessage::Info {
-            name: "Cold Clear 2",
-            version: concat!(env!("CARGO_PKG_VERSION"), " ", env!("GIT_HASH")),
-            author: "MinusKelvin",
-            features: &[],
-        })
-        .await
-        .unwrap();
-
-    let bot = Arc::new(BotSyncronizer::new());
-
-    spawn_workers(&bot);
-
-    let mut waiting_on_first_piece = None;
-
-    while let Some(msg) = incoming.next().await {
-        match msg {
-            FrontendMessage::Start(start) => {
-                if start.hold.is_none() && start.queue.is_empty() {
-                    waiting_on_first_piece = Some(start);
-                } else {
-                    bot.start(create_bot(start, config.clone()));
-                }
-            }
-            FrontendMessage::Stop => {
-                bot.stop();
-                waiting_on_first_piece = None;
-            }
-            FrontendMessage::Suggest => {
-                if let Some((moves, move_info)) = bot.suggest() {
-                    outgoing
-                        .send(BotMessage::Suggestion { moves, move_info })
-                        .await
-                        .unwrap();
-                }
-            }
-            FrontendMessage::Play { mv } => {
-                bot.advance(mv);
-                puffin::GlobalProfiler::lock().new_frame();
-            }
-            FrontendMessage::NewPiece { piece } => {
-                if let Some(mut start) = waiting_on_first_piece.take() {
-                    if let Randomizer::SevenBag { bag_state } = &mut start.randomizer {
-                        if bag_state.is_empty() {
-                            *bag_state = EnumSet::all();
-                        }
-                        bag_state.remove(piece);
-                    }
-                    start.queue.push(piece);
-                    bot.start(create_bot(start, config.clone()));
-                } else {
-                    bot.new_piece(piece);
-                }
-            }
-            FrontendMessage::Rules => {
-                outgoing.send(BotMessage::Ready).await.unwrap();
-            }
-            FrontendMessage::Quit => break,
-            FrontendMessage::Unknown => {}
+    outgoing.send(BotMessage::info()).await.unwrap();
+
+    let pool = Arc::new(WorkerPool::new(config.worker_threads()));
+    let (auto_suggestions, replies) = mpsc::unbounded();
+    let mut session = Session::new(pool, config, move |msg| {
+        let _ = auto_suggestions.unbounded_send(msg);
+    });
+
+    let mut events = stream::select(
+        incoming
+            .map(Event::Message)
+            .chain(stream::iter([Event::Message(FrontendMessage::Quit)])),
+        replies.map(Event::Reply),
+    );
+
+    while let Some(event) = events.next().await {
+        let reply = match event {
+            Event::Message(FrontendMessage::Quit) => break,
+            Event::Message(msg) => session.handle(msg),
+            Event::Reply(reply) => Some(reply),
+            Event::BudgetSpent => None,
+        };
+        if let Some(reply) = reply {
+            outgoing.send(reply).await.unwrap();
         }
     }
 }
 
-fn create_bot(mut start: tbp::Start, config: Arc<BotConfig>) -> Bot {
-    let reserve = start.hold.unwrap_or_else(|| start.queue.remove(0));
+fn tree_message(tree: TreeNode, format: TreeFormat) -> BotMessage {
+    match format {
+        TreeFormat::Json => BotMessage::Tree {
+            tree: Some(tree),
+            dot: None,
+        },
+        TreeFormat::Dot => BotMessage::Tree {
+            tree: None,
+            dot: Some(tree.to_dot()),
+        },
+    }
+}
 
+fn create_bot(start: tbp::Start, config: Arc<BotConfig>) -> Bot {
     let speculate = matches!(start.randomizer, Randomizer::SevenBag { .. });
+    let (state, queue) = start_position(start, &config);
+    Bot::new(BotOptions { speculate, config }, state, &queue)
+}
+
+/// The position described by a `start` message, with the queue after the reserve piece.
+fn start_position(mut start: tbp::Start, config: &BotConfig) -> (GameState, Vec<Piece>) {
+    let garbage_rows = start
+        .board
+        .iter()
+        .take_while(|row| row.contains(&Some('G')))
+        .count();
+    let mut board: Board = start.board.into();
+    board.set_width(config.rules.board.width);
+    let (reserve, hold_empty) = match config.rules.hold {
+        // the reserve piece is never played, so any piece will do
+        HoldMode::Disabled => {
+            let piece = start.hold.or(start.queue.first().copied());
+            (piece.unwrap_or(Piece::I), false)
+        }
+        _ => match start.hold {
+            Some(piece) => (piece, false),
+            None => (start.queue.remove(0), true),
+        },
+    };
+
     let bag = match start.randomizer {
         Randomizer::Unknown => EnumSet::all(),
         Randomizer::SevenBag { mut bag_state } => {
@@ -200,18 +302,15 @@ fn create_bot(mut start: tbp::Start, config: Arc<BotConfig>) -> Bot {
 
     let state = GameState {
         reserve,
-        back_to_back: start.back_to_back,
+        back_to_back: start.back_to_back as u8,
         combo: start.combo.try_into().unwrap_or(255),
         bag,
-        board: start.board.into(),
+        board,
+        garbage: start.garbage.into_iter().map(Into::into).collect(),
+        hold_locked: false,
+        hold_empty,
+        garbage_rows: garbage_rows.min(40) as u8,
     };
 
-    Bot::new(BotOptions { speculate, config }, state, &start.queue)
-}
-
-fn spawn_workers(bot: &Arc<BotSyncronizer>) {
-    for _ in 0..1 {
-        let bot = bot.clone();
-        std::thread::spawn(move || bot.work_loop());
-    }
+    (state, start.queue)
 }
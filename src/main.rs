@@ -1,19 +1,37 @@
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener};
 use std::path::PathBuf;
 use std::sync::Arc;
 
+use cold_clear_2::bot::BotConfig;
+use cold_clear_2::session::{run_multiplexed, WorkerPool};
+use futures::channel::mpsc::{self, UnboundedReceiver};
+use serde::de::DeserializeOwned;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
 struct CliOptions {
     /// Enable puffin profiler (requires building with feature `puffin_http`)
     #[structopt(long)]
+    #[cfg_attr(not(feature = "puffin_http"), allow(dead_code))]
     profile: bool,
 
     /// Path to JSON file containing the bot configuration
     #[structopt(short, long)]
     config: Option<PathBuf>,
+
+    /// Number of search threads, overriding the configuration [default: available parallelism]
+    #[structopt(short, long)]
+    threads: Option<usize>,
+
+    /// Host any number of bots over stdin/stdout, with messages tagged by a `session` id
+    #[structopt(long)]
+    multiplex: bool,
+
+    /// Host any number of bots for TCP clients, which talk like with `--multiplex`
+    #[structopt(long)]
+    listen: Option<SocketAddr>,
 }
 
 fn main() {
@@ -31,25 +49,91 @@ fn main() {
         false => None,
     };
 
-    let config = options.config.map_or_else(Default::default, |path| {
+    let mut config: BotConfig = options.config.map_or_else(Default::default, |path| {
         let f = BufReader::new(File::open(path).unwrap());
-        Arc::new(serde_json::from_reader(f).unwrap())
+        serde_json::from_reader(f).unwrap()
     });
+    if options.threads.is_some() {
+        config.threads = options.threads;
+    }
 
-    let incoming = futures::stream::repeat_with(|| {
-        let mut line = String::new();
-        std::io::stdin().read_line(&mut line).unwrap();
-        serde_json::from_str(&line).unwrap()
-    });
+    let config = Arc::new(config);
+
+    if let Some(address) = options.listen {
+        let pool = Arc::new(WorkerPool::new(config.worker_threads()));
+        let listener = TcpListener::bind(address).unwrap();
+        for connection in listener.incoming() {
+            let connection = match connection {
+                Ok(connection) => connection,
+                Err(_) => continue,
+            };
+            let input = BufReader::new(connection.try_clone().unwrap());
+            let pool = pool.clone();
+            let config = config.clone();
+            std::thread::spawn(move || serve_multiplexed(input, connection, pool, config));
+        }
+    } else if options.multiplex {
+        let pool = Arc::new(WorkerPool::new(config.worker_threads()));
+        serve_multiplexed(
+            BufReader::new(std::io::stdin()),
+            std::io::stdout(),
+            pool,
+            config,
+        );
+    } else {
+        let incoming = read_messages(BufReader::new(std::io::stdin()));
 
-    let outgoing = futures::sink::unfold((), |_, msg| {
-        serde_json::to_writer(std::io::stdout(), &msg).unwrap();
-        println!();
-        async { Ok(()) }
+        let outgoing = futures::sink::unfold((), |_, msg| {
+            serde_json::to_writer(std::io::stdout(), &msg).unwrap();
+            println!();
+            async { Ok(()) }
+        });
+
+        futures::pin_mut!(outgoing);
+
+        futures::executor::block_on(cold_clear_2::run(incoming, outgoing, config));
+    }
+}
+
+fn serve_multiplexed(
+    input: impl BufRead + Send + 'static,
+    output: impl Write,
+    pool: Arc<WorkerPool>,
+    config: Arc<BotConfig>,
+) {
+    let incoming = read_messages(input);
+
+    let outgoing = futures::sink::unfold(output, |mut output, msg| {
+        // a client that went away stops sending messages too, which ends its sessions
+        let _ = serde_json::to_writer(&mut output, &msg);
+        let _ = writeln!(output);
+        async { Ok(output) }
     });
 
-    futures::pin_mut!(incoming);
     futures::pin_mut!(outgoing);
 
-    futures::executor::block_on(cold_clear_2::run(incoming, outgoing, config));
+    futures::executor::block_on(run_multiplexed(incoming, outgoing, pool, config));
+}
+
+/// Reads messages on their own thread, so the bot can send moves while waiting for messages.
+fn read_messages<T: DeserializeOwned + Send + 'static>(
+    input: impl BufRead + Send + 'static,
+) -> UnboundedReceiver<T> {
+    let (sender, incoming) = mpsc::unbounded();
+    std::thread::spawn(move || {
+        for line in input.lines().map_while(Result::ok) {
+            let msg = match serde_json::from_str(&line) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    // clients are untrusted, so a bad message shouldn't take the others down
+                    eprintln!("ignoring malformed message: {e}");
+                    continue;
+                }
+            };
+            if sender.unbounded_send(msg).is_err() {
+                break;
+            }
+        }
+    });
+    incoming
 }
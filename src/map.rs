@@ -1,7 +1,5 @@
 use std::convert::TryInto;
 use std::hash::BuildHasher;
-use std::hash::Hash;
-use std::hash::Hasher;
 
 use nohash::IntMap;
 use parking_lot::MappedRwLockReadGuard;
@@ -36,24 +34,22 @@ impl<V, S: Default> Default for StateMap<V, S> {
 
 impl<V, S: BuildHasher> StateMap<V, S> {
     pub fn index(&self, k: &GameState) -> u64 {
-        let mut hasher = self.hasher.build_hasher();
-        k.hash(&mut hasher);
-        hasher.finish()
+        self.hasher.hash_one(k)
     }
 
     fn bucket(&self, k: u64) -> &RwLock<IntMap<u64, V>> {
         &self.buckets[(k >> SHARD_INDEX_SHIFT) as usize % SHARDS]
     }
 
-    pub fn get_raw(&self, k: u64) -> Option<MappedRwLockReadGuard<V>> {
+    pub fn get_raw(&self, k: u64) -> Option<MappedRwLockReadGuard<'_, V>> {
         RwLockReadGuard::try_map(self.bucket(k).read(), |shard| shard.get(&k)).ok()
     }
 
-    pub fn get(&self, k: &GameState) -> Option<MappedRwLockReadGuard<V>> {
+    pub fn get(&self, k: &GameState) -> Option<MappedRwLockReadGuard<'_, V>> {
         self.get_raw(self.index(k))
     }
 
-    pub fn get_raw_mut(&self, k: u64) -> Option<MappedRwLockWriteGuard<V>> {
+    pub fn get_raw_mut(&self, k: u64) -> Option<MappedRwLockWriteGuard<'_, V>> {
         RwLockWriteGuard::try_map(self.bucket(k).write(), |shard| shard.get_mut(&k)).ok()
     }
 
@@ -61,7 +57,7 @@ impl<V, S: BuildHasher> StateMap<V, S> {
         &self,
         k: u64,
         f: impl FnOnce() -> V,
-    ) -> MappedRwLockWriteGuard<V> {
+    ) -> MappedRwLockWriteGuard<'_, V> {
         RwLockWriteGuard::map(self.bucket(k).write(), |shard| {
             shard.entry(k).or_insert_with(f)
         })
@@ -71,9 +67,34 @@ impl<V, S: BuildHasher> StateMap<V, S> {
         &self,
         k: &GameState,
         f: impl FnOnce() -> V,
-    ) -> MappedRwLockWriteGuard<V> {
+    ) -> MappedRwLockWriteGuard<'_, V> {
         self.get_raw_or_insert_with(self.index(k), f)
     }
+
+    /// Calls `f` with the raw index and value of every entry.
+    pub fn for_each_mut(&mut self, mut f: impl FnMut(u64, &mut V)) {
+        for shard in self.buckets.iter_mut() {
+            for (&k, v) in shard.get_mut() {
+                f(k, v);
+            }
+        }
+    }
+
+    /// An empty map that hashes states the same way, so raw indices stay valid in it.
+    pub fn empty_like<T>(&self) -> StateMap<T, S>
+    where
+        S: Clone,
+    {
+        StateMap {
+            hasher: self.hasher.clone(),
+            buckets: std::iter::repeat_with(|| RwLock::new(IntMap::default()))
+                .take(SHARDS)
+                .collect::<Box<_>>()
+                .try_into()
+                .unwrap_or_else(|_| unreachable!()),
+        }
+    }
+
     pub fn map_values<T>(self, f: impl Fn(V) -> T) -> StateMap<T, S> {
         StateMap {
             hasher: self.hasher,
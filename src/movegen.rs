@@ -4,87 +4,84 @@ use std::collections::BinaryHeap;
 use ahash::AHashMap;
 
 use crate::data::*;
+use crate::rules::{GameRules, Gravity, Rotate180, SpinRule};
 
-pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
+mod kicks;
+mod path;
+
+pub use self::kicks::{KickTable, RotationSystem};
+pub use self::path::{Handling, Input, Minimize, Path, Paths};
+
+/// Time taken to reach a placement along the path found, see [`Handling::minimize`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MoveCost {
+    pub frames: u32,
+    pub soft_drops: u32,
+    /// Number of inputs, not counting the hard drop.
+    pub inputs: u32,
+}
+
+/// The way each position was first reached, by the position before it and the input used.
+type Predecessors = AHashMap<Placement, (Placement, Input)>;
+
+pub fn find_moves(board: &Board, piece: Piece, rules: &GameRules) -> Vec<(Placement, MoveCost)> {
     puffin::profile_function!();
+    let hard_drop = rules.handling.frames(Input::HardDrop, 1);
+    search(board, piece, rules, None)
+        .into_iter()
+        .map(|(mv, (_, cost))| {
+            let cost = MoveCost {
+                frames: cost.frames + hard_drop,
+                ..cost
+            };
+            (mv, cost)
+        })
+        .collect()
+}
+
+/// Finds the cheapest way to reach every placement of `piece`. Returns, for each canonical
+/// placement, the position it is hard dropped from and the cost of getting there. If
+/// `predecessors` is given, the path to every position is recorded in it.
+fn search(
+    board: &Board,
+    piece: Piece,
+    rules: &GameRules,
+    mut predecessors: Option<&mut Predecessors>,
+) -> AHashMap<Placement, (Placement, MoveCost)> {
     let mut queue = BinaryHeap::new();
     let mut values = AHashMap::new();
-    let mut underground_locks = AHashMap::new();
-    let mut locks = Vec::with_capacity(64);
+    let mut locks = AHashMap::new();
     let collision_map = CollisionMaps::new(board, piece);
+    let kicks = rules.rotation_system.table();
 
-    let fast_mode = board.cols.iter().all(|&c| c.leading_zeros() > 64 - 16);
-    if fast_mode {
-        for &rotation in &[
-            Rotation::North,
-            Rotation::East,
-            Rotation::South,
-            Rotation::West,
-        ] {
-            for x in 0..10 {
-                let mut location = PieceLocation {
-                    piece,
-                    rotation,
-                    x,
-                    y: 19,
-                };
-                if collision_map.obstructed(location) {
-                    continue;
-                }
-                let distance = location.drop_distance(board);
-                location.y -= distance;
-                let mv = Placement {
-                    location,
-                    spin: Spin::None,
-                };
-
-                let mut update_position =
-                    update_position(&mut queue, &mut values, fast_mode, board);
-
-                if let Some(mv) = shift(location, &collision_map, -1) {
-                    update_position(mv, distance as u32);
-                }
-                if let Some(mv) = shift(location, &collision_map, 1) {
-                    update_position(mv, distance as u32);
-                }
-                if let Some(mv) = rotate_cw(location, &collision_map, board) {
-                    update_position(mv, distance as u32);
-                }
-                if let Some(mv) = rotate_ccw(location, &collision_map, board) {
-                    update_position(mv, distance as u32);
-                }
-
-                if location.canonical_form() == location {
-                    locks.push((mv, 0));
-                }
-            }
-        }
-    } else {
-        let mut spawned = PieceLocation {
-            piece,
-            rotation: Rotation::North,
-            x: 4,
-            y: 19,
-        };
-        if collision_map.obstructed(spawned) {
-            spawned.y += 1;
-            if collision_map.obstructed(spawned) {
-                return vec![];
-            }
-        }
-        let spawned = Placement {
-            location: spawned,
-            spin: Spin::None,
-        };
-        queue.push(Intermediate {
-            soft_drops: 0,
-            mv: spawned,
-        });
-        values.insert(spawned, 0);
-    }
+    let spawned = match spawn_location(&collision_map, piece, rules.board.spawn()) {
+        Some(location) => fall(location, board, rules.gravity, 1),
+        None => return locks,
+    };
+    let minimize = rules.handling.minimize;
+    queue.push(Intermediate {
+        key: cost_key(MoveCost::default(), minimize),
+        cost: MoveCost::default(),
+        mv: spawned,
+    });
+    values.insert(spawned, MoveCost::default());
+
+    // With a low stack, anything above it that isn't at spawn height is reached more cheaply by
+    // moving at spawn height and then dropping. Gravity doesn't let pieces stay at spawn height.
+    let fast_mode = rules.gravity == Gravity::None
+        && board
+            .cols()
+            .iter()
+            .all(|&c| 64 - (c.leading_zeros() as i8) < spawned.location.y - 3);
+    let prune = |input: Input, target: Placement| {
+        fast_mode
+            && input != Input::SoftDrop
+            && target.location.y < spawned.location.y
+            && target.location.above_stack(board)
+    };
 
     while let Some(expand) = queue.pop() {
-        if expand.soft_drops != values.get(&expand.mv).copied().unwrap_or(40) {
+        if values.get(&expand.mv) != Some(&expand.cost) {
             continue;
         }
 
@@ -101,55 +98,167 @@ pub fn find_moves(board: &Board, piece: Piece) -> Vec<(Placement, u32)> {
             },
         };
 
-        let sds = underground_locks
+        // positions are expanded cheapest first, so the first way to lock is the fastest
+        locks
             .entry(Placement {
                 location: dropped.location.canonical_form(),
                 ..dropped
             })
-            .or_insert(expand.soft_drops);
-        *sds = expand.soft_drops.min(*sds);
+            .or_insert((expand.mv, expand.cost));
+
+        successors(
+            expand.mv,
+            &collision_map,
+            board,
+            rules,
+            kicks,
+            |input, target, cells| {
+                if prune(input, target) {
+                    return;
+                }
+                let cost = MoveCost {
+                    frames: expand.cost.frames + rules.handling.frames(input, cells),
+                    soft_drops: expand.cost.soft_drops
+                        + if input == Input::SoftDrop { cells } else { 0 },
+                    inputs: expand.cost.inputs + 1,
+                };
+                let key = cost_key(cost, minimize);
+                let improved = match values.get(&target) {
+                    Some(&prev) => key < cost_key(prev, minimize),
+                    None => true,
+                };
+                if improved {
+                    values.insert(target, cost);
+                    if let Some(predecessors) = predecessors.as_deref_mut() {
+                        predecessors.insert(target, (expand.mv, input));
+                    }
+                    queue.push(Intermediate {
+                        key,
+                        cost,
+                        mv: target,
+                    });
+                }
+            },
+        );
+    }
 
-        let mut update_position = update_position(&mut queue, &mut values, fast_mode, board);
+    locks
+}
 
-        update_position(dropped, expand.soft_drops + drop_dist as u32);
+/// Order in which paths are preferred.
+fn cost_key(cost: MoveCost, minimize: Minimize) -> (u32, u32, u32) {
+    match minimize {
+        Minimize::Frames => (cost.frames, cost.inputs, cost.soft_drops),
+        Minimize::Inputs => (cost.inputs, cost.frames, cost.soft_drops),
+    }
+}
 
-        if let Some(mv) = shift(expand.mv.location, &collision_map, -1) {
-            update_position(mv, expand.soft_drops);
-        }
-        if let Some(mv) = shift(expand.mv.location, &collision_map, 1) {
-            update_position(mv, expand.soft_drops);
-        }
-        if let Some(mv) = rotate_cw(expand.mv.location, &collision_map, board) {
-            update_position(mv, expand.soft_drops);
-        }
-        if let Some(mv) = rotate_ccw(expand.mv.location, &collision_map, board) {
-            update_position(mv, expand.soft_drops);
+fn spawn_location(
+    collision_map: &CollisionMaps,
+    piece: Piece,
+    (x, y): (i8, i8),
+) -> Option<PieceLocation> {
+    let mut spawned = PieceLocation {
+        piece,
+        rotation: Rotation::North,
+        x,
+        y,
+    };
+    if collision_map.obstructed(spawned) {
+        spawned.y += 1;
+        if collision_map.obstructed(spawned) {
+            return None;
         }
     }
+    Some(spawned)
+}
 
-    locks.extend(underground_locks.into_iter());
-    locks
+/// Where the piece ends up after `frames` frames of gravity.
+fn fall(location: PieceLocation, board: &Board, gravity: Gravity, frames: u32) -> Placement {
+    let distance = gravity.cells(frames).min(location.drop_distance(board));
+    Placement {
+        location: PieceLocation {
+            y: location.y - distance,
+            ..location
+        },
+        spin: Spin::None,
+    }
 }
 
-fn update_position<'a>(
-    queue: &'a mut BinaryHeap<Intermediate>,
-    values: &'a mut AHashMap<Placement, u32>,
-    fast_mode: bool,
-    board: &'a Board,
-) -> impl FnMut(Placement, u32) + 'a {
-    move |target: Placement, soft_drops: u32| {
-        if fast_mode && target.location.above_stack(board) {
-            return;
+/// Calls `f` with every position one input away from `from`, along with the number of cells the
+/// input moved the piece. Gravity is applied after each input, and after each cell of DAS.
+fn successors(
+    from: Placement,
+    collision_map: &CollisionMaps,
+    board: &Board,
+    rules: &GameRules,
+    kicks: &KickTable,
+    mut f: impl FnMut(Input, Placement, u32),
+) {
+    let location = from.location;
+    let handling = &rules.handling;
+    // a piece that doesn't fall keeps its spin
+    let settle = |mv: Placement, frames: u32| match fall(mv.location, board, rules.gravity, frames)
+    {
+        fallen if fallen.location == mv.location => mv,
+        fallen => fallen,
+    };
+
+    let drop_dist = location.drop_distance(board);
+    if drop_dist > 0 {
+        let dropped = Placement {
+            location: PieceLocation {
+                y: location.y - drop_dist,
+                ..location
+            },
+            spin: Spin::None,
+        };
+        f(Input::SoftDrop, dropped, drop_dist as u32);
+    }
+
+    for (tap, das, dx) in [
+        (Input::Left, Input::DasLeft, -1),
+        (Input::Right, Input::DasRight, 1),
+    ] {
+        let mut moved = location;
+        let mut cells = 0;
+        while let Some(mv) = shift(moved, collision_map, dx) {
+            let frames = match cells {
+                0 => handling.das,
+                _ => handling.arr,
+            };
+            if cells == 0 {
+                f(tap, settle(mv, handling.tap), 1);
+            }
+            moved = settle(mv, frames).location;
+            cells += 1;
         }
-        let prev_sds = values.entry(target).or_insert(40);
-        if soft_drops < *prev_sds {
-            *prev_sds = soft_drops;
-            queue.push(Intermediate {
-                soft_drops,
-                mv: target,
-            });
+        if cells > 1 {
+            let mv = Placement {
+                location: moved,
+                spin: Spin::None,
+            };
+            f(das, mv, cells);
         }
     }
+
+    let frames = handling.rotate;
+    if let Some(mv) = rotate_cw(location, collision_map, board, kicks, rules.spins) {
+        f(Input::Cw, settle(mv, frames), 1);
+    }
+    if let Some(mv) = rotate_ccw(location, collision_map, board, kicks, rules.spins) {
+        f(Input::Ccw, settle(mv, frames), 1);
+    }
+    if let Some(mv) = rotate_180(
+        location,
+        collision_map,
+        board,
+        kicks,
+        rules.rotate_180,
+        rules.spins,
+    ) {
+        f(Input::Rotate180, settle(mv, frames), 1);
+    }
 }
 
 fn shift(mut location: PieceLocation, collision_map: &CollisionMaps, dx: i8) -> Option<Placement> {
@@ -167,12 +276,12 @@ fn rotate_cw(
     from: PieceLocation,
     collision_map: &CollisionMaps,
     board: &Board,
+    kicks: &KickTable,
+    spins: SpinRule,
 ) -> Option<Placement> {
     if from.piece == Piece::O {
         return None;
     }
-    const KICKS: [[[(i8, i8); 5]; 4]; 7] =
-        piece_lut!(piece => rotation_lut!(rotation => kicks(piece, rotation, rotation.cw())));
     let unkicked = PieceLocation {
         rotation: from.rotation.cw(),
         ..from
@@ -181,9 +290,9 @@ fn rotate_cw(
         unkicked,
         collision_map,
         board,
-        KICKS[from.piece as usize][from.rotation as usize]
-            .iter()
-            .copied(),
+        kicks.cw(from.piece, from.rotation).iter().copied(),
+        kicks.full_spin_kick(),
+        spins,
     )
 }
 
@@ -191,12 +300,12 @@ fn rotate_ccw(
     from: PieceLocation,
     collision_map: &CollisionMaps,
     board: &Board,
+    kicks: &KickTable,
+    spins: SpinRule,
 ) -> Option<Placement> {
     if from.piece == Piece::O {
         return None;
     }
-    const KICKS: [[[(i8, i8); 5]; 4]; 7] =
-        piece_lut!(piece => rotation_lut!(rotation => kicks(piece, rotation, rotation.ccw())));
     let unkicked = PieceLocation {
         rotation: from.rotation.ccw(),
         ..from
@@ -205,45 +314,38 @@ fn rotate_ccw(
         unkicked,
         collision_map,
         board,
-        KICKS[from.piece as usize][from.rotation as usize]
-            .iter()
-            .copied(),
+        kicks.ccw(from.piece, from.rotation).iter().copied(),
+        kicks.full_spin_kick(),
+        spins,
     )
 }
 
-const fn offsets(piece: Piece, rotation: Rotation) -> [(i8, i8); 5] {
-    match piece {
-        Piece::O => match rotation {
-            Rotation::North => [(0, 0); 5],
-            Rotation::East => [(0, -1); 5],
-            Rotation::South => [(-1, -1); 5],
-            Rotation::West => [(-1, 0); 5],
-        },
-        Piece::I => match rotation {
-            Rotation::North => [(0, 0), (-1, 0), (2, 0), (-1, 0), (2, 0)],
-            Rotation::East => [(-1, 0), (0, 0), (0, 0), (0, 1), (0, -2)],
-            Rotation::South => [(-1, 1), (1, 1), (-2, 1), (1, 0), (-2, 0)],
-            Rotation::West => [(0, 1), (0, 1), (0, 1), (0, -1), (0, 2)],
-        },
-        _ => match rotation {
-            Rotation::North => [(0, 0); 5],
-            Rotation::East => [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],
-            Rotation::South => [(0, 0); 5],
-            Rotation::West => [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],
-        },
-    }
-}
-
-const fn kicks(piece: Piece, from: Rotation, to: Rotation) -> [(i8, i8); 5] {
-    let mut kicks = [(0, 0); 5];
-    let from = offsets(piece, from);
-    let to = offsets(piece, to);
-    let mut i = 0;
-    while i < kicks.len() {
-        kicks[i] = (from[i].0 - to[i].0, from[i].1 - to[i].1);
-        i += 1;
+fn rotate_180(
+    from: PieceLocation,
+    collision_map: &CollisionMaps,
+    board: &Board,
+    kicks: &KickTable,
+    rotate_180: Rotate180,
+    spins: SpinRule,
+) -> Option<Placement> {
+    if from.piece == Piece::O {
+        return None;
     }
-    kicks
+    let unkicked = PieceLocation {
+        rotation: from.rotation.flip(),
+        ..from
+    };
+    rotate(
+        unkicked,
+        collision_map,
+        board,
+        kicks
+            .flip(rotate_180, from.piece, from.rotation)
+            .iter()
+            .copied(),
+        None,
+        spins,
+    )
 }
 
 fn rotate(
@@ -251,6 +353,8 @@ fn rotate(
     collision_map: &CollisionMaps,
     board: &Board,
     kicks: impl Iterator<Item = (i8, i8)>,
+    full_spin_kick: Option<usize>,
+    spins: SpinRule,
 ) -> Option<Placement> {
     for (i, (dx, dy)) in kicks.enumerate() {
         let target = PieceLocation {
@@ -262,28 +366,15 @@ fn rotate(
             continue;
         }
 
-        let spin;
-        if target.piece != Piece::T {
-            spin = Spin::None;
-        } else {
-            let corners = [(-1, -1), (1, -1), (-1, 1), (1, 1)]
-                .iter()
-                .filter(|&&(cx, cy)| board.occupied((cx + target.x, cy + target.y)))
-                .count();
-            let mini_corners = [(-1, 1), (1, 1)]
-                .iter()
-                .map(|&c| target.rotation.rotate_cell(c))
-                .filter(|&(cx, cy)| board.occupied((cx + target.x, cy + target.y)))
-                .count();
-
-            if corners < 3 {
-                spin = Spin::None;
-            } else if mini_corners == 2 || i == 4 {
-                spin = Spin::Full;
-            } else {
-                spin = Spin::Mini;
+        let spin = match (spins, target.piece) {
+            (SpinRule::TSpins | SpinRule::TSpinsAllMini, Piece::T) => {
+                three_corner_spin(target, board, full_spin_kick == Some(i))
             }
-        }
+            (SpinRule::TSpins, _) => Spin::None,
+            _ if !immobile(target, collision_map) => Spin::None,
+            (SpinRule::AllSpin, _) => Spin::Full,
+            (SpinRule::AllMini | SpinRule::TSpinsAllMini, _) => Spin::Mini,
+        };
 
         return Some(Placement {
             location: target,
@@ -294,21 +385,53 @@ fn rotate(
     None
 }
 
+fn three_corner_spin(target: PieceLocation, board: &Board, full_spin_kick: bool) -> Spin {
+    let corners = [(-1, -1), (1, -1), (-1, 1), (1, 1)]
+        .iter()
+        .filter(|&&(cx, cy)| board.occupied((cx + target.x, cy + target.y)))
+        .count();
+    let mini_corners = [(-1, 1), (1, 1)]
+        .iter()
+        .map(|&c| target.rotation.rotate_cell(c))
+        .filter(|&(cx, cy)| board.occupied((cx + target.x, cy + target.y)))
+        .count();
+
+    if corners < 3 {
+        Spin::None
+    } else if mini_corners == 2 || full_spin_kick {
+        Spin::Full
+    } else {
+        Spin::Mini
+    }
+}
+
+fn immobile(target: PieceLocation, collision_map: &CollisionMaps) -> bool {
+    [(-1, 0), (1, 0), (0, 1)].iter().all(|&(dx, dy)| {
+        collision_map.obstructed(PieceLocation {
+            x: target.x + dx,
+            y: target.y + dy,
+            ..target
+        })
+    })
+}
+
 #[derive(Clone, Copy, Debug, Eq)]
 struct Intermediate {
     mv: Placement,
-    soft_drops: u32,
+    cost: MoveCost,
+    key: (u32, u32, u32),
 }
 
 impl PartialEq for Intermediate {
     fn eq(&self, other: &Intermediate) -> bool {
-        self.soft_drops == other.soft_drops
+        self.key == other.key
     }
 }
 
 impl Ord for Intermediate {
     fn cmp(&self, other: &Intermediate) -> Ordering {
-        self.soft_drops.cmp(&other.soft_drops)
+        // cheapest first
+        other.key.cmp(&self.key)
     }
 }
 
@@ -319,24 +442,27 @@ impl PartialOrd for Intermediate {
 }
 
 struct CollisionMaps {
-    boards: [[u64; 10]; 4],
+    /// Columns past the width of the board are fully obstructed.
+    boards: [[u64; MAX_WIDTH]; 4],
 }
 
 impl CollisionMaps {
     fn new(board: &Board, piece: Piece) -> Self {
-        let mut boards = [[0; 10]; 4];
+        let mut boards = [[!0; MAX_WIDTH]; 4];
         for rot in [
             Rotation::North,
             Rotation::West,
             Rotation::South,
             Rotation::East,
         ] {
+            boards[rot as usize][..board.width() as usize].fill(0);
             for (dx, dy) in rot.rotate_cells(piece.cells()) {
-                for x in 0..10 {
-                    let c = board.cols.get((x + dx) as usize).copied().unwrap_or(!0);
+                for x in 0..board.width() {
+                    // rows from 40 up are off the board
+                    let c = board.cols().get((x + dx) as usize).copied().unwrap_or(!0) | !0 << 40;
                     let c = match dy < 0 {
                         true => !(!c << -dy),
-                        false => c >> dy,
+                        false => c >> dy | !(!0 >> dy),
                     };
                     boards[rot as usize][x as usize] |= c;
                 }
@@ -346,7 +472,7 @@ impl CollisionMaps {
     }
 
     fn obstructed(&self, piece: PieceLocation) -> bool {
-        let v = piece.y < 0
+        let v = !(0..64).contains(&piece.y)
             || self.boards[piece.rotation as usize]
                 .get(piece.x as usize)
                 .map(|&c| c & 1 << piece.y != 0)
@@ -354,3 +480,120 @@ impl CollisionMaps {
         v
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIECES: [Piece; 7] = [
+        Piece::I,
+        Piece::O,
+        Piece::T,
+        Piece::L,
+        Piece::J,
+        Piece::S,
+        Piece::Z,
+    ];
+
+    #[rustfmt::skip]
+    fn boards() -> Vec<Board> {
+        vec![
+            Board::default(),
+            Board::from_cols(&[
+                0b00111111,
+                0b00111111,
+                0b00011111,
+                0b00000111,
+                0b00000001,
+                0b00000000,
+                0b00001101,
+                0b00011111,
+                0b00111111,
+                0b11111111,
+            ]),
+        ]
+    }
+
+    #[test]
+    fn paths_agree_with_moves() {
+        for gravity in [Gravity::None, Gravity::Cells(0.5), Gravity::TwentyG] {
+            let rules = GameRules {
+                gravity,
+                ..GameRules::default()
+            };
+            for board in boards() {
+                for piece in PIECES {
+                    let paths = Paths::new(&board, piece, &rules);
+                    for (mv, cost) in find_moves(&board, piece, &rules) {
+                        let path = paths.path(&rules, mv).unwrap();
+                        assert_eq!(path.frames, cost.frames, "{:?} {:?}", gravity, mv);
+                        assert_eq!(path.inputs.len() as u32, cost.inputs + 1);
+                        assert_eq!(path.inputs.last(), Some(&Input::HardDrop));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn minimize_inputs() {
+        let frames = GameRules::default();
+        let mut inputs = GameRules::default();
+        inputs.handling.minimize = Minimize::Inputs;
+        for board in boards() {
+            for piece in PIECES {
+                let fastest: AHashMap<_, _> =
+                    find_moves(&board, piece, &frames).into_iter().collect();
+                for (mv, cost) in find_moves(&board, piece, &inputs) {
+                    let fastest = fastest[&mv];
+                    assert!(cost.inputs <= fastest.inputs, "{:?}", mv);
+                    assert!(cost.frames >= fastest.frames, "{:?}", mv);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn board_rules_are_kept_in_range() {
+        for board_rules in [
+            r#"{"width": 40, "height": 255}"#,
+            r#"{"width": 0, "height": 0}"#,
+            r#"{"width": 6, "spawn": [100, 120]}"#,
+            r#"{"spawn": [-5, -5]}"#,
+        ] {
+            let rules = GameRules {
+                board: serde_json::from_str(board_rules).unwrap(),
+                ..GameRules::default()
+            };
+            let board = Board::new(rules.board.width);
+            for piece in PIECES {
+                let moves = find_moves(&board, piece, &rules);
+                assert!(!moves.is_empty(), "{}", board_rules);
+                for (mv, _) in moves {
+                    let mut board = board;
+                    board.place(mv.location);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tspin_is_found() {
+        let board = boards()[1];
+        let rules = GameRules::default();
+        let moves = find_moves(&board, Piece::T, &rules);
+        let tsd = moves
+            .iter()
+            .find(|(mv, _)| {
+                mv.location
+                    == PieceLocation {
+                        piece: Piece::T,
+                        rotation: Rotation::South,
+                        x: 5,
+                        y: 1,
+                    }
+            })
+            .expect("t-spin double slot not found");
+        assert_eq!(tsd.0.spin, Spin::Full);
+    }
+}
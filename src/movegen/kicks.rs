@@ -0,0 +1,525 @@
+use std::collections::HashMap;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{Piece, Rotation};
+use crate::rules::Rotate180;
+
+/// Kick offsets indexed by piece and the rotation being rotated from, in the order they are
+/// tried. Offsets are relative to rotating the cells of the piece around its origin.
+type Kicks = [[Vec<(i8, i8)>; 4]; 7];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum RotationSystem {
+    #[default]
+    Srs,
+    /// SRS with symmetric I kicks and 180 kicks, as used by TETR.IO.
+    SrsPlus,
+    /// SRS with the 180 kicks of SRS-X from Heboris U.E.
+    SrsX,
+    /// Approximation of the TGM rotation system: bottom-aligned rotation states with a right
+    /// and a left kick. The center column rule, which stops L, J and T from kicking when the
+    /// cell blocking the rotation is in the middle column of their box, depends on the board
+    /// and can't be expressed as a kick table, so those kicks are always tried.
+    Ars,
+    Custom(Box<KickTable>),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "RawKickTable", into = "RawKickTable")]
+pub struct KickTable {
+    cw: Kicks,
+    ccw: Kicks,
+    flip: Option<Kicks>,
+    full_spin_kick: Option<usize>,
+}
+
+impl RotationSystem {
+    pub fn table(&self) -> &KickTable {
+        static SRS: Lazy<KickTable> = Lazy::new(|| KickTable {
+            cw: build(|piece, rotation| srs(piece, rotation, rotation.cw())),
+            ccw: build(|piece, rotation| srs(piece, rotation, rotation.ccw())),
+            flip: None,
+            full_spin_kick: Some(4),
+        });
+        static SRS_PLUS: Lazy<KickTable> = Lazy::new(|| KickTable {
+            cw: build(|piece, rotation| srs_plus(piece, rotation, rotation.cw())),
+            ccw: build(|piece, rotation| srs_plus(piece, rotation, rotation.ccw())),
+            flip: Some(TETRIO_180.clone()),
+            full_spin_kick: Some(4),
+        });
+        static SRS_X: Lazy<KickTable> = Lazy::new(|| KickTable {
+            flip: Some(SRS_X_180.clone()),
+            ..SRS.clone()
+        });
+        static ARS: Lazy<KickTable> = Lazy::new(|| KickTable {
+            cw: build(|piece, rotation| ars(piece, rotation, rotation.cw())),
+            ccw: build(|piece, rotation| ars(piece, rotation, rotation.ccw())),
+            flip: None,
+            full_spin_kick: None,
+        });
+        match self {
+            RotationSystem::Srs => &SRS,
+            RotationSystem::SrsPlus => &SRS_PLUS,
+            RotationSystem::SrsX => &SRS_X,
+            RotationSystem::Ars => &ARS,
+            RotationSystem::Custom(table) => table,
+        }
+    }
+}
+
+impl KickTable {
+    pub fn cw(&self, piece: Piece, from: Rotation) -> &[(i8, i8)] {
+        &self.cw[piece as usize][from as usize]
+    }
+
+    pub fn ccw(&self, piece: Piece, from: Rotation) -> &[(i8, i8)] {
+        &self.ccw[piece as usize][from as usize]
+    }
+
+    /// Kicks for 180 rotation. The rotation system's own 180 kicks take precedence over the ones
+    /// selected by `rotate_180`, but 180 rotation is only possible if `rotate_180` enables it.
+    pub fn flip(&self, rotate_180: Rotate180, piece: Piece, from: Rotation) -> &[(i8, i8)] {
+        let fallback = match rotate_180 {
+            Rotate180::Disabled => return &[],
+            Rotate180::Tetrio => &*TETRIO_180,
+            Rotate180::Nullpomino => &*SRS_X_180,
+        };
+        &self.flip.as_ref().unwrap_or(fallback)[piece as usize][from as usize]
+    }
+
+    /// Index of the kick which always produces a full spin, like the SRS T-spin triple kick.
+    pub fn full_spin_kick(&self) -> Option<usize> {
+        self.full_spin_kick
+    }
+}
+
+static TETRIO_180: Lazy<Kicks> = Lazy::new(|| {
+    build(|piece, rotation| {
+        let kicks: &[_] = match rotation {
+            Rotation::North => &[(0, 0), (0, 1), (1, 1), (-1, 1), (1, 0), (-1, 0)],
+            Rotation::East => &[(0, 0), (1, 0), (1, 2), (1, 1), (0, 2), (0, 1)],
+            Rotation::South => &[(0, 0), (0, -1), (-1, -1), (1, -1), (-1, 0), (1, 0)],
+            Rotation::West => &[(0, 0), (-1, 0), (-1, 2), (-1, 1), (0, 2), (0, 1)],
+        };
+        true_rotation(piece, rotation, rotation.flip(), kicks)
+    })
+});
+
+/// The SRS-X 180 kicks, which NullpoMino uses as well. Heboris lists them with y pointing down,
+/// so the vertical kicks are negated here.
+static SRS_X_180: Lazy<Kicks> = Lazy::new(|| {
+    build(|piece, rotation| {
+        #[rustfmt::skip]
+        let kicks: &[_] = match (piece, rotation) {
+            (Piece::I, Rotation::North) => &[(0, 0), (-1, 0), (-2, 0), (1, 0), (2, 0), (0, -1)],
+            (Piece::I, Rotation::East) => &[(0, 0), (0, -1), (0, -2), (0, 1), (0, 2), (-1, 0)],
+            (Piece::I, Rotation::South) => &[(0, 0), (1, 0), (2, 0), (-1, 0), (-2, 0), (0, 1)],
+            (Piece::I, Rotation::West) => &[(0, 0), (0, -1), (0, -2), (0, 1), (0, 2), (1, 0)],
+            (_, Rotation::North) => &[
+                (0, 0), (1, 0), (2, 0), (1, -1), (2, -1), (-1, 0),
+                (-2, 0), (-1, -1), (-2, -1), (0, 1), (3, 0), (-3, 0),
+            ],
+            (_, Rotation::East) => &[
+                (0, 0), (0, -1), (0, -2), (-1, -1), (-1, -2), (0, 1),
+                (0, 2), (-1, 1), (-1, 2), (1, 0), (0, -3), (0, 3),
+            ],
+            (_, Rotation::South) => &[
+                (0, 0), (-1, 0), (-2, 0), (-1, 1), (-2, 1), (1, 0),
+                (2, 0), (1, 1), (2, 1), (0, -1), (-3, 0), (3, 0),
+            ],
+            (_, Rotation::West) => &[
+                (0, 0), (0, -1), (0, -2), (1, -1), (1, -2), (0, 1),
+                (0, 2), (1, 1), (1, 2), (-1, 0), (0, -3), (0, 3),
+            ],
+        };
+        true_rotation(piece, rotation, rotation.flip(), kicks)
+    })
+});
+
+fn build(f: impl Fn(Piece, Rotation) -> Vec<(i8, i8)>) -> Kicks {
+    piece_lut!(piece => rotation_lut!(rotation => f(piece, rotation)))
+}
+
+const fn offsets(piece: Piece, rotation: Rotation) -> [(i8, i8); 5] {
+    match piece {
+        Piece::O => match rotation {
+            Rotation::North => [(0, 0); 5],
+            Rotation::East => [(0, -1); 5],
+            Rotation::South => [(-1, -1); 5],
+            Rotation::West => [(-1, 0); 5],
+        },
+        Piece::I => match rotation {
+            Rotation::North => [(0, 0), (-1, 0), (2, 0), (-1, 0), (2, 0)],
+            Rotation::East => [(-1, 0), (0, 0), (0, 0), (0, 1), (0, -2)],
+            Rotation::South => [(-1, 1), (1, 1), (-2, 1), (1, 0), (-2, 0)],
+            Rotation::West => [(0, 1), (0, 1), (0, 1), (0, -1), (0, 2)],
+        },
+        _ => match rotation {
+            Rotation::North => [(0, 0); 5],
+            Rotation::East => [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],
+            Rotation::South => [(0, 0); 5],
+            Rotation::West => [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],
+        },
+    }
+}
+
+fn srs(piece: Piece, from: Rotation, to: Rotation) -> Vec<(i8, i8)> {
+    let from = offsets(piece, from);
+    let to = offsets(piece, to);
+    from.iter()
+        .zip(to.iter())
+        .map(|(f, t)| (f.0 - t.0, f.1 - t.1))
+        .collect()
+}
+
+fn srs_plus(piece: Piece, from: Rotation, to: Rotation) -> Vec<(i8, i8)> {
+    if piece != Piece::I {
+        return srs(piece, from, to);
+    }
+    let kicks: &[(i8, i8)] = match (from, to) {
+        (Rotation::North, Rotation::East) => &[(0, 0), (1, 0), (-2, 0), (-2, -1), (1, 2)],
+        (Rotation::East, Rotation::North) => &[(0, 0), (-1, 0), (2, 0), (-1, -2), (2, 1)],
+        (Rotation::East, Rotation::South) => &[(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)],
+        (Rotation::South, Rotation::East) => &[(0, 0), (-2, 0), (1, 0), (-2, 1), (1, -2)],
+        (Rotation::South, Rotation::West) => &[(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)],
+        (Rotation::West, Rotation::South) => &[(0, 0), (1, 0), (-2, 0), (1, 2), (-2, -1)],
+        (Rotation::West, Rotation::North) => &[(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)],
+        (Rotation::North, Rotation::West) => &[(0, 0), (-1, 0), (2, 0), (2, 1), (-1, -2)],
+        _ => unreachable!(),
+    };
+    true_rotation(piece, from, to, kicks)
+}
+
+fn ars(piece: Piece, from: Rotation, to: Rotation) -> Vec<(i8, i8)> {
+    // Position of each rotation state relative to the rotation center of the ARS box
+    let position = |rotation| match (piece, rotation) {
+        (Piece::I, Rotation::North) => (0, 0),
+        (Piece::I, Rotation::West) => (1, -1),
+        (Piece::I, _) => (1, 0),
+        (Piece::S | Piece::Z, Rotation::North) => (0, -1),
+        (Piece::S | Piece::Z, Rotation::East) => (-1, 0),
+        (_, Rotation::North) => (0, -1),
+        _ => (0, 0),
+    };
+    let (fx, fy) = position(from);
+    let (tx, ty) = position(to);
+    let base = (tx - fx, ty - fy);
+    match piece {
+        Piece::I => vec![base],
+        _ => vec![base, (base.0 + 1, base.1), (base.0 - 1, base.1)],
+    }
+}
+
+/// Converts kicks given relative to the true rotation of a piece, which for some pieces differs
+/// from rotating its cells around the origin.
+fn true_rotation(piece: Piece, from: Rotation, to: Rotation, kicks: &[(i8, i8)]) -> Vec<(i8, i8)> {
+    let (bx, by) = srs(piece, from, to)[0];
+    kicks.iter().map(|&(dx, dy)| (dx + bx, dy + by)).collect()
+}
+
+type RawKicks = HashMap<Piece, HashMap<Rotation, Vec<(i8, i8)>>>;
+
+#[derive(Serialize, Deserialize)]
+struct RawKickTable {
+    cw: RawKicks,
+    ccw: RawKicks,
+    #[serde(default)]
+    flip: Option<RawKicks>,
+    #[serde(default)]
+    full_spin_kick: Option<usize>,
+}
+
+impl From<RawKickTable> for KickTable {
+    fn from(raw: RawKickTable) -> Self {
+        let convert = |raw: &RawKicks| {
+            build(|piece, rotation| {
+                raw.get(&piece)
+                    .and_then(|r| r.get(&rotation))
+                    .cloned()
+                    .unwrap_or_default()
+            })
+        };
+        KickTable {
+            cw: convert(&raw.cw),
+            ccw: convert(&raw.ccw),
+            flip: raw.flip.as_ref().map(convert),
+            full_spin_kick: raw.full_spin_kick,
+        }
+    }
+}
+
+impl From<KickTable> for RawKickTable {
+    fn from(table: KickTable) -> Self {
+        let convert = |kicks: &Kicks| -> RawKicks {
+            piece_lut!(piece => piece)
+                .into_iter()
+                .map(|piece| {
+                    let rotations = rotation_lut!(rotation => rotation)
+                        .into_iter()
+                        .map(|rotation| {
+                            (rotation, kicks[piece as usize][rotation as usize].clone())
+                        })
+                        .collect();
+                    (piece, rotations)
+                })
+                .collect()
+        };
+        RawKickTable {
+            cw: convert(&table.cw),
+            ccw: convert(&table.ccw),
+            flip: table.flip.as_ref().map(convert),
+            full_spin_kick: table.full_spin_kick,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::PieceLocation;
+
+    const ROTATIONS: [Rotation; 4] = [
+        Rotation::North,
+        Rotation::East,
+        Rotation::South,
+        Rotation::West,
+    ];
+
+    /// Kicks in the usual published form, relative to the first kick, for rotating clockwise from
+    /// each rotation starting at north.
+    type Published = [[(i8, i8); 5]; 4];
+
+    #[rustfmt::skip]
+    const SRS_JLSTZ_CW: Published = [
+        [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)],
+        [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],
+        [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],
+        [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],
+    ];
+    #[rustfmt::skip]
+    const SRS_JLSTZ_CCW: Published = [
+        [(0, 0), (1, 0), (1, 1), (0, -2), (1, -2)],
+        [(0, 0), (1, 0), (1, -1), (0, 2), (1, 2)],
+        [(0, 0), (-1, 0), (-1, 1), (0, -2), (-1, -2)],
+        [(0, 0), (-1, 0), (-1, -1), (0, 2), (-1, 2)],
+    ];
+    #[rustfmt::skip]
+    const SRS_I_CW: Published = [
+        [(0, 0), (-2, 0), (1, 0), (-2, -1), (1, 2)],
+        [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)],
+        [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)],
+        [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)],
+    ];
+    #[rustfmt::skip]
+    const SRS_I_CCW: Published = [
+        [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)],
+        [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)],
+        [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)],
+        [(0, 0), (-2, 0), (1, 0), (-2, -1), (1, 2)],
+    ];
+    #[rustfmt::skip]
+    const SRS_PLUS_I_CW: Published = [
+        [(0, 0), (1, 0), (-2, 0), (-2, -1), (1, 2)],
+        [(0, 0), (-1, 0), (2, 0), (-1, 2), (2, -1)],
+        [(0, 0), (2, 0), (-1, 0), (2, 1), (-1, -2)],
+        [(0, 0), (1, 0), (-2, 0), (1, -2), (-2, 1)],
+    ];
+    #[rustfmt::skip]
+    const SRS_PLUS_I_CCW: Published = [
+        [(0, 0), (-1, 0), (2, 0), (2, 1), (-1, -2)],
+        [(0, 0), (-1, 0), (2, 0), (-1, -2), (2, 1)],
+        [(0, 0), (-2, 0), (1, 0), (-2, 1), (1, -2)],
+        [(0, 0), (1, 0), (-2, 0), (1, 2), (-2, -1)],
+    ];
+
+    /// The SRS-X 180 kicks as Heboris lists them, with y pointing down, from each rotation
+    /// starting at north.
+    #[rustfmt::skip]
+    const SRS_X_180_JLSTZ: [[(i8, i8); 12]; 4] = [
+        [
+            (0, 0), (1, 0), (2, 0), (1, 1), (2, 1), (-1, 0),
+            (-2, 0), (-1, 1), (-2, 1), (0, -1), (3, 0), (-3, 0),
+        ],
+        [
+            (0, 0), (0, 1), (0, 2), (-1, 1), (-1, 2), (0, -1),
+            (0, -2), (-1, -1), (-1, -2), (1, 0), (0, 3), (0, -3),
+        ],
+        [
+            (0, 0), (-1, 0), (-2, 0), (-1, -1), (-2, -1), (1, 0),
+            (2, 0), (1, -1), (2, -1), (0, 1), (-3, 0), (3, 0),
+        ],
+        [
+            (0, 0), (0, 1), (0, 2), (1, 1), (1, 2), (0, -1),
+            (0, -2), (1, -1), (1, -2), (-1, 0), (0, 3), (0, -3),
+        ],
+    ];
+    #[rustfmt::skip]
+    const SRS_X_180_I: [[(i8, i8); 6]; 4] = [
+        [(0, 0), (-1, 0), (-2, 0), (1, 0), (2, 0), (0, 1)],
+        [(0, 0), (0, 1), (0, 2), (0, -1), (0, -2), (-1, 0)],
+        [(0, 0), (1, 0), (2, 0), (-1, 0), (-2, 0), (0, -1)],
+        [(0, 0), (0, 1), (0, 2), (0, -1), (0, -2), (1, 0)],
+    ];
+
+    fn y_up(kicks: &[(i8, i8)]) -> Vec<(i8, i8)> {
+        kicks.iter().map(|&(x, y)| (x, -y)).collect()
+    }
+
+    fn relative(kicks: &[(i8, i8)]) -> Vec<(i8, i8)> {
+        let (bx, by) = kicks[0];
+        kicks.iter().map(|&(x, y)| (x - bx, y - by)).collect()
+    }
+
+    fn check(system: RotationSystem, pieces: &[Piece], cw: &Published, ccw: &Published) {
+        let table = system.table();
+        for &piece in pieces {
+            for (i, &from) in ROTATIONS.iter().enumerate() {
+                assert_eq!(
+                    relative(table.cw(piece, from)),
+                    cw[i],
+                    "{:?} {:?} cw from {:?}",
+                    system,
+                    piece,
+                    from
+                );
+                assert_eq!(
+                    relative(table.ccw(piece, from)),
+                    ccw[i],
+                    "{:?} {:?} ccw from {:?}",
+                    system,
+                    piece,
+                    from
+                );
+            }
+        }
+    }
+
+    /// Cells of `piece` rotated to `to` from the origin using the first of `kicks`.
+    fn rotated(piece: Piece, to: Rotation, kicks: &[(i8, i8)]) -> [(i8, i8); 4] {
+        let (dx, dy) = kicks[0];
+        PieceLocation {
+            piece,
+            rotation: to,
+            x: dx,
+            y: dy,
+        }
+        .cells()
+    }
+
+    fn sorted(mut cells: [(i8, i8); 4]) -> [(i8, i8); 4] {
+        cells.sort_unstable();
+        cells
+    }
+
+    #[test]
+    fn srs() {
+        let jlstz = [Piece::T, Piece::L, Piece::J, Piece::S, Piece::Z];
+        check(RotationSystem::Srs, &jlstz, &SRS_JLSTZ_CW, &SRS_JLSTZ_CCW);
+        check(RotationSystem::Srs, &[Piece::I], &SRS_I_CW, &SRS_I_CCW);
+    }
+
+    #[test]
+    fn srs_plus() {
+        let jlstz = [Piece::T, Piece::L, Piece::J, Piece::S, Piece::Z];
+        check(
+            RotationSystem::SrsPlus,
+            &jlstz,
+            &SRS_JLSTZ_CW,
+            &SRS_JLSTZ_CCW,
+        );
+        check(
+            RotationSystem::SrsPlus,
+            &[Piece::I],
+            &SRS_PLUS_I_CW,
+            &SRS_PLUS_I_CCW,
+        );
+
+        let table = RotationSystem::SrsPlus.table();
+        assert_eq!(
+            relative(table.flip(Rotate180::Tetrio, Piece::T, Rotation::North)),
+            [(0, 0), (0, 1), (1, 1), (-1, 1), (1, 0), (-1, 0)]
+        );
+        assert_eq!(
+            relative(table.flip(Rotate180::Tetrio, Piece::T, Rotation::East)),
+            [(0, 0), (1, 0), (1, 2), (1, 1), (0, 2), (0, 1)]
+        );
+    }
+
+    #[test]
+    fn srs_i_true_rotation() {
+        // the I piece turns within its 4x4 box, rather than around the cell at its origin
+        let table = RotationSystem::Srs.table();
+        let east = rotated(
+            Piece::I,
+            Rotation::East,
+            table.cw(Piece::I, Rotation::North),
+        );
+        assert_eq!(sorted(east), [(1, -2), (1, -1), (1, 0), (1, 1)]);
+        let west = rotated(
+            Piece::I,
+            Rotation::West,
+            table.ccw(Piece::I, Rotation::North),
+        );
+        assert_eq!(sorted(west), [(0, -2), (0, -1), (0, 0), (0, 1)]);
+    }
+
+    #[test]
+    fn ars() {
+        let table = RotationSystem::Ars.table();
+        for piece in [Piece::T, Piece::L, Piece::J, Piece::S, Piece::Z] {
+            for from in ROTATIONS {
+                for (to, kicks) in [
+                    (from.cw(), table.cw(piece, from)),
+                    (from.ccw(), table.ccw(piece, from)),
+                ] {
+                    // the kicks are the basic rotation, then one cell right, then one left
+                    let (bx, by) = kicks[0];
+                    assert_eq!(kicks, [(bx, by), (bx + 1, by), (bx - 1, by)]);
+
+                    // rotation states sit on the bottom of the 3x3 box
+                    let before = PieceLocation {
+                        piece,
+                        rotation: from,
+                        x: 0,
+                        y: 0,
+                    };
+                    let bottom = |cells: [(i8, i8); 4]| cells.iter().map(|c| c.1).min();
+                    let after = rotated(piece, to, kicks);
+                    assert_eq!(
+                        bottom(before.cells()),
+                        bottom(after),
+                        "{:?} from {:?} to {:?}",
+                        piece,
+                        from,
+                        to
+                    );
+                }
+            }
+        }
+        assert_eq!(table.cw(Piece::I, Rotation::North).len(), 1);
+    }
+
+    #[test]
+    fn srs_x() {
+        let table = RotationSystem::SrsX.table();
+        for (i, from) in ROTATIONS.into_iter().enumerate() {
+            for piece in [Piece::T, Piece::L, Piece::J, Piece::S, Piece::Z] {
+                // the rotation system's own 180 kicks are used whichever ones are selected
+                let kicks = table.flip(Rotate180::Tetrio, piece, from);
+                assert_eq!(relative(kicks), y_up(&SRS_X_180_JLSTZ[i]));
+            }
+            let kicks = table.flip(Rotate180::Tetrio, Piece::I, from);
+            assert_eq!(relative(kicks), y_up(&SRS_X_180_I[i]));
+        }
+        assert!(table
+            .flip(Rotate180::Disabled, Piece::T, Rotation::North)
+            .is_empty());
+
+        let jlstz = [Piece::T, Piece::L, Piece::J, Piece::S, Piece::Z];
+        check(RotationSystem::SrsX, &jlstz, &SRS_JLSTZ_CW, &SRS_JLSTZ_CCW);
+        check(RotationSystem::SrsX, &[Piece::I], &SRS_I_CW, &SRS_I_CCW);
+    }
+}
@@ -0,0 +1,126 @@
+use ahash::AHashMap;
+use serde::{Deserialize, Serialize};
+
+use super::{search, MoveCost, Predecessors};
+use crate::data::*;
+use crate::rules::GameRules;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Input {
+    Left,
+    Right,
+    /// Hold left until the piece stops moving.
+    DasLeft,
+    /// Hold right until the piece stops moving.
+    DasRight,
+    Cw,
+    Ccw,
+    Rotate180,
+    /// Soft drop until the piece lands.
+    SoftDrop,
+    HardDrop,
+    Hold,
+}
+
+/// Timing of the inputs of whoever plays the moves, in frames.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(default)]
+pub struct Handling {
+    pub das: u32,
+    pub arr: u32,
+    /// Frames per cell of soft drop. Zero means soft drop is instant.
+    pub soft_drop: u32,
+    /// Frames taken by a single tap left or right.
+    pub tap: u32,
+    pub rotate: u32,
+    pub hard_drop: u32,
+    pub hold: u32,
+    /// What the paths to placements are chosen by first. Ties are broken by the other.
+    pub minimize: Minimize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Minimize {
+    #[default]
+    Frames,
+    /// The fewest inputs, for frontends that are limited by inputs rather than time.
+    Inputs,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Path {
+    pub inputs: Vec<Input>,
+    pub frames: u32,
+}
+
+/// Input sequences from spawn to every placement reachable by a piece, as found by
+/// [`find_moves`](super::find_moves). By default these are the fastest, and of those the ones with
+/// the fewest inputs; [`Handling::minimize`] can put the number of inputs first instead.
+pub struct Paths {
+    predecessors: Predecessors,
+    /// The position each canonical placement is hard dropped from, and the cost of reaching it.
+    locks: AHashMap<Placement, (Placement, MoveCost)>,
+}
+
+impl Default for Handling {
+    fn default() -> Self {
+        Handling {
+            das: 10,
+            arr: 2,
+            soft_drop: 2,
+            tap: 1,
+            rotate: 1,
+            hard_drop: 1,
+            hold: 1,
+            minimize: Minimize::Frames,
+        }
+    }
+}
+
+impl Handling {
+    /// Frames taken by `input` when it moves the piece `cells` cells.
+    pub fn frames(&self, input: Input, cells: u32) -> u32 {
+        match input {
+            Input::Left | Input::Right => self.tap,
+            Input::DasLeft | Input::DasRight => self.das + self.arr * cells.saturating_sub(1),
+            Input::Cw | Input::Ccw | Input::Rotate180 => self.rotate,
+            Input::SoftDrop => (self.soft_drop * cells).max(1),
+            Input::HardDrop => self.hard_drop,
+            Input::Hold => self.hold,
+        }
+    }
+}
+
+impl Paths {
+    pub fn new(board: &Board, piece: Piece, rules: &GameRules) -> Self {
+        puffin::profile_function!();
+        let mut predecessors = Predecessors::default();
+        let locks = search(board, piece, rules, Some(&mut predecessors));
+        Paths {
+            predecessors,
+            locks,
+        }
+    }
+
+    /// Inputs that place the piece at `target`, ending with a hard drop.
+    pub fn path(&self, rules: &GameRules, target: Placement) -> Option<Path> {
+        let key = Placement {
+            location: target.location.canonical_form(),
+            ..target
+        };
+        let &(end, cost) = self.locks.get(&key)?;
+        let mut inputs = vec![Input::HardDrop];
+        let mut current = end;
+        while let Some(&(prev, input)) = self.predecessors.get(&current) {
+            inputs.push(input);
+            current = prev;
+        }
+        inputs.reverse();
+        Some(Path {
+            inputs,
+            frames: cost.frames + rules.handling.frames(Input::HardDrop, 1),
+        })
+    }
+}
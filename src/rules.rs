@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data::MAX_WIDTH;
+use crate::movegen::{Handling, RotationSystem};
+
+mod attack;
+
+pub use self::attack::{AttackRules, AttackTable};
+
+/// Rules of the game being played, as opposed to the bot's own configuration.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GameRules {
+    pub attack: AttackRules,
+    pub rotation_system: RotationSystem,
+    pub rotate_180: Rotate180,
+    pub spins: SpinRule,
+    pub handling: Handling,
+    pub gravity: Gravity,
+    pub board: BoardRules,
+    pub hold: HoldMode,
+}
+
+/// Shape of the playfield. Values out of range are clamped when read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(from = "RawBoardRules")]
+pub struct BoardRules {
+    /// Number of columns, from 4 up to [`MAX_WIDTH`].
+    pub width: u8,
+    /// Number of rows visible to the player, up to [`MAX_HEIGHT`]. Pieces above this are still
+    /// playable.
+    pub height: u8,
+    /// Where pieces spawn, in north orientation. Defaults to the middle of the top visible row,
+    /// rounding left. Kept to where a piece in north orientation fits on the board.
+    pub spawn: Option<(i8, i8)>,
+}
+
+/// Highest visible height supported, leaving room above it for pieces to spawn within the 40
+/// rows the bot keeps track of.
+pub const MAX_HEIGHT: u8 = 38;
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct RawBoardRules {
+    width: u8,
+    height: u8,
+    spawn: Option<(i8, i8)>,
+}
+
+impl Default for BoardRules {
+    fn default() -> Self {
+        BoardRules {
+            width: 10,
+            height: 20,
+            spawn: None,
+        }
+    }
+}
+
+impl BoardRules {
+    pub fn spawn(&self) -> (i8, i8) {
+        let width = self.width.clamp(4, MAX_WIDTH as u8) as i8;
+        let (x, y) = self
+            .spawn
+            .unwrap_or(((width - 1) / 2, self.height.min(MAX_HEIGHT) as i8 - 1));
+        // every piece reaches one cell left and up and two cells right of its origin
+        (x.clamp(1, width - 3), y.clamp(0, MAX_HEIGHT as i8))
+    }
+}
+
+impl Default for RawBoardRules {
+    fn default() -> Self {
+        let rules = BoardRules::default();
+        RawBoardRules {
+            width: rules.width,
+            height: rules.height,
+            spawn: rules.spawn,
+        }
+    }
+}
+
+impl From<RawBoardRules> for BoardRules {
+    fn from(raw: RawBoardRules) -> Self {
+        let mut rules = BoardRules {
+            width: raw.width.clamp(4, MAX_WIDTH as u8),
+            height: raw.height.clamp(1, MAX_HEIGHT),
+            spawn: raw.spawn,
+        };
+        rules.spawn = raw.spawn.map(|_| rules.spawn());
+        rules
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Rotate180 {
+    #[default]
+    Disabled,
+    /// SRS+ 180 kicks as used by TETR.IO.
+    Tetrio,
+    /// SRS-X 180 kicks as used by NullpoMino.
+    Nullpomino,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldMode {
+    #[default]
+    Enabled,
+    /// There is no hold; every piece is played in queue order.
+    Disabled,
+    /// Hold can't be used on two placements in a row, so a piece put into hold stays there for
+    /// at least one more placement. The guideline's rule of holding once per piece needs no
+    /// mode of its own, since every placement uses hold at most once.
+    Restricted,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case", from = "RawGravity")]
+pub enum Gravity {
+    /// Pieces only move down when soft or hard dropped.
+    #[default]
+    None,
+    /// Pieces fall this many cells per frame of input, rounded down. Fractions of a cell are not
+    /// carried over between inputs. Kept within 0 to 40 cells when read.
+    Cells(f32),
+    /// Pieces drop to the stack as soon as they spawn and after every input, as in TGM's 20G.
+    #[serde(rename = "20g")]
+    TwentyG,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum RawGravity {
+    None,
+    Cells(f32),
+    #[serde(rename = "20g")]
+    TwentyG,
+}
+
+impl From<RawGravity> for Gravity {
+    fn from(raw: RawGravity) -> Self {
+        match raw {
+            RawGravity::None => Gravity::None,
+            // gravity never moves pieces up
+            RawGravity::Cells(g) if g.is_nan() => Gravity::Cells(0.0),
+            RawGravity::Cells(g) => Gravity::Cells(g.clamp(0.0, 40.0)),
+            RawGravity::TwentyG => Gravity::TwentyG,
+        }
+    }
+}
+
+impl Gravity {
+    /// Number of cells a piece falls in `frames` frames, if unobstructed.
+    pub fn cells(self, frames: u32) -> i8 {
+        match self {
+            Gravity::None => 0,
+            Gravity::Cells(g) => (g * frames as f32).min(40.0) as i8,
+            Gravity::TwentyG => 40,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum SpinRule {
+    /// T-spins detected with the 3-corner rule. Other pieces never spin.
+    #[default]
+    TSpins,
+    /// Any piece that cannot move left, right or up after rotating is a full spin.
+    AllSpin,
+    /// Any piece that cannot move left, right or up after rotating is a mini spin.
+    AllMini,
+    /// T-spins detected with the 3-corner rule, and immobile non-T pieces are mini spins.
+    TSpinsAllMini,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn gravity_is_clamped_when_read() {
+        let read = |json: &str| serde_json::from_str::<Gravity>(json).unwrap();
+        assert_eq!(read(r#"{"cells": -2.5}"#), Gravity::Cells(0.0));
+        assert_eq!(read(r#"{"cells": 0.5}"#), Gravity::Cells(0.5));
+        assert_eq!(read(r#"{"cells": 100}"#), Gravity::Cells(40.0));
+        assert_eq!(read(r#""20g""#), Gravity::TwentyG);
+        assert_eq!(read(r#""none""#), Gravity::None);
+
+        let nan = Gravity::from(RawGravity::Cells(f32::NAN));
+        assert_eq!(nan, Gravity::Cells(0.0));
+        assert_eq!(nan.cells(60), 0);
+    }
+}
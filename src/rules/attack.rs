@@ -0,0 +1,293 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{PlacementInfo, Spin};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AttackRules {
+    #[default]
+    Guideline,
+    Tetrio,
+    Jstris,
+    PuyoPuyoTetris,
+    Custom(AttackTable),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AttackTable {
+    pub clears: [u32; 5],
+    pub mini_spin_clears: [u32; 3],
+    pub spin_clears: [u32; 4],
+    pub back_to_back: u32,
+    /// Back-to-back chain lengths at which the back-to-back bonus increases by one more line.
+    #[serde(default)]
+    pub back_to_back_levels: Vec<u32>,
+    /// Lines added to a clear, indexed by the number of consecutive line clears it completes.
+    /// The last entry repeats.
+    #[serde(default)]
+    pub combo_table: Vec<u32>,
+    /// Multiplies attack by `1 + combo_multiplier * combo` instead of using the combo table.
+    /// Clears that would send nothing send `ln(1 + 5 * combo_multiplier * combo)` lines from the
+    /// second combo on.
+    #[serde(default)]
+    pub combo_multiplier: f32,
+    pub perfect_clear: u32,
+    /// Whether a perfect clear sends only the perfect clear bonus.
+    #[serde(default)]
+    pub perfect_clear_replaces: bool,
+}
+
+impl AttackRules {
+    pub fn table(&self) -> &AttackTable {
+        static GUIDELINE: Lazy<AttackTable> = Lazy::new(|| AttackTable {
+            clears: [0, 0, 1, 2, 4],
+            mini_spin_clears: [0, 0, 1],
+            spin_clears: [0, 2, 4, 6],
+            back_to_back: 1,
+            back_to_back_levels: vec![],
+            combo_table: vec![0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5],
+            combo_multiplier: 0.0,
+            perfect_clear: 10,
+            perfect_clear_replaces: true,
+        });
+        static TETRIO: Lazy<AttackTable> = Lazy::new(|| AttackTable {
+            clears: [0, 0, 1, 2, 4],
+            mini_spin_clears: [0, 0, 1],
+            spin_clears: [0, 2, 4, 6],
+            back_to_back: 1,
+            back_to_back_levels: vec![3, 8, 24, 67, 185],
+            combo_table: vec![],
+            combo_multiplier: 0.25,
+            perfect_clear: 10,
+            perfect_clear_replaces: false,
+        });
+        static JSTRIS: Lazy<AttackTable> = Lazy::new(|| AttackTable {
+            clears: [0, 0, 1, 2, 4],
+            mini_spin_clears: [0, 0, 1],
+            spin_clears: [0, 2, 4, 6],
+            back_to_back: 1,
+            back_to_back_levels: vec![],
+            combo_table: vec![0, 0, 0, 1, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5],
+            combo_multiplier: 0.0,
+            perfect_clear: 10,
+            perfect_clear_replaces: false,
+        });
+        static PUYO_PUYO_TETRIS: Lazy<AttackTable> = Lazy::new(|| AttackTable {
+            clears: [0, 0, 1, 2, 4],
+            mini_spin_clears: [0, 0, 1],
+            spin_clears: [0, 2, 4, 6],
+            back_to_back: 1,
+            back_to_back_levels: vec![],
+            combo_table: vec![0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 4, 5],
+            combo_multiplier: 0.0,
+            perfect_clear: 10,
+            perfect_clear_replaces: false,
+        });
+        match self {
+            AttackRules::Guideline => &GUIDELINE,
+            AttackRules::Tetrio => &TETRIO,
+            AttackRules::Jstris => &JSTRIS,
+            AttackRules::PuyoPuyoTetris => &PUYO_PUYO_TETRIS,
+            AttackRules::Custom(table) => table,
+        }
+    }
+}
+
+impl AttackTable {
+    /// Longest back-to-back chain that still makes a difference to the attack.
+    pub fn max_back_to_back_chain(&self) -> u8 {
+        self.back_to_back_levels
+            .last()
+            .copied()
+            .unwrap_or(1)
+            .clamp(1, u8::MAX as u32) as u8
+    }
+
+    /// Lines sent by a placement, where `chain` is the back-to-back chain it continues.
+    pub fn attack(&self, info: &PlacementInfo, chain: u8) -> u32 {
+        if info.lines_cleared == 0 {
+            return 0;
+        }
+        let lines = info.lines_cleared as usize;
+        let mut attack = match info.placement.spin {
+            Spin::None => self.clears.get(lines),
+            Spin::Mini => self.mini_spin_clears.get(lines),
+            Spin::Full => self.spin_clears.get(lines),
+        }
+        .copied()
+        .unwrap_or_else(|| self.clears[lines.min(4)]);
+
+        if info.back_to_back {
+            attack += self.back_to_back
+                + self
+                    .back_to_back_levels
+                    .iter()
+                    .filter(|&&level| chain as u32 >= level)
+                    .count() as u32;
+        }
+
+        let combo = info.combo.saturating_sub(1);
+        if self.combo_multiplier > 0.0 {
+            if attack == 0 && combo >= 2 {
+                attack = (1.0 + 5.0 * self.combo_multiplier * combo as f32).ln() as u32;
+            } else {
+                attack = (attack as f32 * (1.0 + self.combo_multiplier * combo as f32)) as u32;
+            }
+        } else if let Some(&last) = self.combo_table.last() {
+            attack += self
+                .combo_table
+                .get(info.combo as usize)
+                .copied()
+                .unwrap_or(last);
+        }
+
+        if info.perfect_clear {
+            if self.perfect_clear_replaces {
+                attack = self.perfect_clear;
+            } else {
+                attack += self.perfect_clear;
+            }
+        }
+
+        attack
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{Piece, PieceLocation, Placement, Rotation};
+
+    fn info(lines: u32, spin: Spin, back_to_back: bool, combo: u32, pc: bool) -> PlacementInfo {
+        PlacementInfo {
+            placement: Placement {
+                location: PieceLocation {
+                    piece: Piece::T,
+                    rotation: Rotation::North,
+                    x: 4,
+                    y: 0,
+                },
+                spin,
+            },
+            lines_cleared: lines,
+            combo,
+            back_to_back,
+            perfect_clear: pc,
+            attack: 0,
+            garbage_cancelled: 0,
+            garbage_received: 0,
+            garbage_cleared: 0,
+        }
+    }
+
+    /// Lines cleared, spin, back-to-back chain, combo counting this clear, perfect clear and the
+    /// expected attack.
+    type Case = (u32, Spin, u8, u32, bool, u32);
+
+    fn check(rules: AttackRules, cases: &[Case]) {
+        let table = rules.table();
+        for &(lines, spin, chain, combo, pc, expected) in cases {
+            let info = info(lines, spin, chain > 0, combo, pc);
+            assert_eq!(
+                table.attack(&info, chain),
+                expected,
+                "{:?}: {} lines, {:?}, chain {}, combo {}, pc {}",
+                rules,
+                lines,
+                spin,
+                chain,
+                combo,
+                pc,
+            );
+        }
+    }
+
+    #[test]
+    fn guideline() {
+        check(
+            AttackRules::Guideline,
+            &[
+                (1, Spin::None, 0, 1, false, 0),
+                (2, Spin::None, 0, 1, false, 1),
+                (3, Spin::None, 0, 1, false, 2),
+                (4, Spin::None, 0, 1, false, 4),
+                (1, Spin::Mini, 0, 1, false, 0),
+                (2, Spin::Mini, 0, 1, false, 1),
+                (1, Spin::Full, 0, 1, false, 2),
+                (2, Spin::Full, 0, 1, false, 4),
+                (3, Spin::Full, 0, 1, false, 6),
+                (4, Spin::None, 1, 1, false, 5),
+                (2, Spin::Full, 5, 1, false, 5),
+                (1, Spin::None, 0, 2, false, 1),
+                (1, Spin::None, 0, 4, false, 2),
+                (2, Spin::None, 0, 5, false, 3),
+                (1, Spin::None, 0, 12, false, 5),
+                (1, Spin::None, 0, 30, false, 5),
+                (4, Spin::None, 0, 1, true, 10),
+                (1, Spin::None, 0, 4, true, 10),
+            ],
+        );
+    }
+
+    #[test]
+    fn tetrio() {
+        check(
+            AttackRules::Tetrio,
+            &[
+                (1, Spin::None, 0, 1, false, 0),
+                (2, Spin::None, 0, 1, false, 1),
+                (3, Spin::None, 0, 1, false, 2),
+                (4, Spin::None, 0, 1, false, 4),
+                (2, Spin::Mini, 0, 1, false, 1),
+                (2, Spin::Full, 0, 1, false, 4),
+                (3, Spin::Full, 0, 1, false, 6),
+                (4, Spin::None, 1, 1, false, 5),
+                (4, Spin::None, 2, 1, false, 5),
+                (4, Spin::None, 3, 1, false, 6),
+                (4, Spin::None, 8, 1, false, 7),
+                (4, Spin::None, 24, 1, false, 8),
+                (2, Spin::Full, 67, 1, false, 9),
+                (4, Spin::None, 185, 1, false, 10),
+                (2, Spin::None, 0, 3, false, 1),
+                (4, Spin::None, 0, 3, false, 6),
+                (2, Spin::Full, 0, 5, false, 8),
+                (1, Spin::None, 0, 2, false, 0),
+                (1, Spin::None, 0, 3, false, 1),
+                (1, Spin::None, 0, 7, false, 2),
+                (4, Spin::None, 0, 1, true, 14),
+                (1, Spin::None, 0, 1, true, 10),
+            ],
+        );
+    }
+
+    #[test]
+    fn jstris() {
+        check(
+            AttackRules::Jstris,
+            &[
+                (4, Spin::None, 0, 1, false, 4),
+                (2, Spin::Full, 1, 1, false, 5),
+                (1, Spin::None, 0, 2, false, 0),
+                (1, Spin::None, 0, 3, false, 1),
+                (1, Spin::None, 0, 6, false, 2),
+                (1, Spin::None, 0, 13, false, 5),
+                (2, Spin::None, 0, 1, true, 11),
+            ],
+        );
+    }
+
+    #[test]
+    fn puyo_puyo_tetris() {
+        check(
+            AttackRules::PuyoPuyoTetris,
+            &[
+                (4, Spin::None, 1, 1, false, 5),
+                (1, Spin::None, 0, 2, false, 0),
+                (1, Spin::None, 0, 3, false, 1),
+                (1, Spin::None, 0, 5, false, 2),
+                (1, Spin::None, 0, 1, true, 10),
+            ],
+        );
+    }
+}
@@ -0,0 +1,388 @@
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use ahash::AHashMap;
+use enumset::EnumSet;
+use futures::channel::mpsc::{self, UnboundedSender};
+use futures::prelude::*;
+use futures::stream;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::bot::BotConfig;
+use crate::sync::BotSyncronizer;
+pub use crate::sync::WorkerPool;
+use crate::tbp::{BotMessage, FrontendMessage, Randomizer, Start};
+use crate::{create_bot, start_position, tree_message};
+
+/// A bot and the state of its conversation with a frontend. The bot searches on the threads of
+/// the pool it was created with, and is removed from the pool when the session is dropped.
+pub struct Session {
+    pool: Arc<WorkerPool>,
+    bot: Arc<BotSyncronizer>,
+    config: Arc<BotConfig>,
+    waiting_on_first_piece: Option<Start>,
+}
+
+impl Session {
+    /// `on_auto_suggestion` is called from a worker thread with the suggestions the bot sends
+    /// without being asked for.
+    pub fn new(
+        pool: Arc<WorkerPool>,
+        config: Arc<BotConfig>,
+        on_auto_suggestion: impl Fn(BotMessage) + Send + Sync + 'static,
+    ) -> Self {
+        let bot = pool.add(move |bot| {
+            if let Some((moves, move_info)) = bot.take_auto_suggestion() {
+                on_auto_suggestion(BotMessage::Suggestion { moves, move_info });
+            }
+        });
+        Session {
+            pool,
+            bot,
+            config,
+            waiting_on_first_piece: None,
+        }
+    }
+
+    /// Handles a message from the frontend, returning the reply if there is one. `quit` is left
+    /// to the caller.
+    pub fn handle(&mut self, msg: FrontendMessage) -> Option<BotMessage> {
+        match msg {
+            FrontendMessage::Start(start) => {
+                if start.hold.is_none() && start.queue.is_empty() {
+                    self.waiting_on_first_piece = Some(start);
+                } else {
+                    self.bot.start(create_bot(start, self.config.clone()));
+                }
+            }
+            FrontendMessage::Resync(start) => {
+                if start.hold.is_none() && start.queue.is_empty() {
+                    self.waiting_on_first_piece = Some(start);
+                    return None;
+                }
+                self.waiting_on_first_piece = None;
+                let (position, queue) = start_position(start.clone(), &self.config);
+                match self.bot.resync(position, &queue) {
+                    Some(recovery) => {
+                        return recovery.map(|recovery| BotMessage::Recovered { recovery });
+                    }
+                    None => self.bot.start(create_bot(start, self.config.clone())),
+                }
+            }
+            FrontendMessage::Stop => {
+                self.bot.stop();
+                self.waiting_on_first_piece = None;
+            }
+            FrontendMessage::Pause => self.bot.pause(),
+            FrontendMessage::Resume => self.bot.resume(),
+            FrontendMessage::Suggest => {
+                return self
+                    .bot
+                    .suggest()
+                    .map(|(moves, move_info)| BotMessage::Suggestion { moves, move_info });
+            }
+            FrontendMessage::Plan { depth } => {
+                return self.bot.plan(depth).map(|moves| BotMessage::Plan { moves });
+            }
+            FrontendMessage::Tree {
+                depth,
+                breadth,
+                format,
+            } => {
+                return self
+                    .bot
+                    .tree(depth, breadth)
+                    .map(|tree| tree_message(tree, format));
+            }
+            FrontendMessage::Play { mv } => {
+                let recovery = self.bot.advance(mv);
+                puffin::GlobalProfiler::lock().new_frame();
+                return recovery.map(|recovery| BotMessage::Recovered { recovery });
+            }
+            FrontendMessage::NewPiece { piece } => {
+                if let Some(mut start) = self.waiting_on_first_piece.take() {
+                    if let Randomizer::SevenBag { bag_state } = &mut start.randomizer {
+                        if bag_state.is_empty() {
+                            *bag_state = EnumSet::all();
+                        }
+                        bag_state.remove(piece);
+                    }
+                    start.queue.push(piece);
+                    self.bot.start(create_bot(start, self.config.clone()));
+                } else {
+                    self.bot.new_piece(piece);
+                }
+            }
+            FrontendMessage::Garbage { garbage } => {
+                if let Some(start) = &mut self.waiting_on_first_piece {
+                    start.garbage = garbage;
+                } else {
+                    self.bot
+                        .set_garbage(garbage.into_iter().map(Into::into).collect());
+                }
+            }
+            FrontendMessage::Rules(rules) => {
+                let mut new_config = (*self.config).clone();
+                rules.apply(&mut new_config.rules);
+                self.config = Arc::new(new_config);
+                return Some(BotMessage::Ready);
+            }
+            FrontendMessage::Limits(limits) => {
+                let mut new_config = (*self.config).clone();
+                new_config.limits = limits;
+                self.config = Arc::new(new_config);
+                self.bot.set_limits(limits);
+            }
+            FrontendMessage::Quit => {}
+            FrontendMessage::Unknown => {}
+        }
+        None
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.pool.remove(&self.bot);
+    }
+}
+
+/// A message to or from one of the sessions of a [`SessionManager`].
+#[derive(Serialize, Deserialize)]
+pub struct Envelope<T> {
+    pub session: String,
+    #[serde(flatten)]
+    pub message: T,
+}
+
+/// Many independent bots, keyed by id, sharing the threads of one pool. Each session has its
+/// own configuration and search limits.
+pub struct SessionManager {
+    pool: Arc<WorkerPool>,
+    config: Arc<BotConfig>,
+    outgoing: UnboundedSender<Envelope<BotMessage>>,
+    sessions: Mutex<AHashMap<String, Arc<Mutex<Session>>>>,
+}
+
+impl SessionManager {
+    /// Replies and suggestions from every session are sent to `outgoing`.
+    pub fn new(
+        pool: Arc<WorkerPool>,
+        config: Arc<BotConfig>,
+        outgoing: UnboundedSender<Envelope<BotMessage>>,
+    ) -> Self {
+        SessionManager {
+            pool,
+            config,
+            outgoing,
+            sessions: Mutex::new(AHashMap::new()),
+        }
+    }
+
+    /// Handles a message for a session. The first message for an id opens the session, which
+    /// then introduces itself like a freshly launched bot would. `quit` closes the session.
+    pub fn handle(&self, id: &str, msg: FrontendMessage) {
+        if let FrontendMessage::Quit = msg {
+            self.close(id);
+            return;
+        }
+        let session = self
+            .sessions
+            .lock()
+            .entry(id.to_owned())
+            .or_insert_with(|| Arc::new(Mutex::new(self.open(id))))
+            .clone();
+        let reply = session.lock().handle(msg);
+        if let Some(message) = reply {
+            self.send(id, message);
+        }
+    }
+
+    /// Closes a session, stopping its bot. Returns whether the session existed.
+    pub fn close(&self, id: &str) -> bool {
+        self.sessions.lock().remove(id).is_some()
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.lock().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn open(&self, id: &str) -> Session {
+        self.send(id, BotMessage::info());
+        let outgoing = self.outgoing.clone();
+        let session = id.to_owned();
+        Session::new(self.pool.clone(), self.config.clone(), move |message| {
+            let _ = outgoing.unbounded_send(Envelope {
+                session: session.clone(),
+                message,
+            });
+        })
+    }
+
+    fn send(&self, id: &str, message: BotMessage) {
+        let _ = self.outgoing.unbounded_send(Envelope {
+            session: id.to_owned(),
+            message,
+        });
+    }
+}
+
+/// Runs any number of bots over one connection, until `incoming` ends. Every message carries
+/// the id of the session it is for in its `session` field.
+pub async fn run_multiplexed(
+    incoming: impl Stream<Item = Envelope<FrontendMessage>> + Unpin,
+    mut outgoing: impl Sink<Envelope<BotMessage>, Error = Infallible> + Unpin,
+    pool: Arc<WorkerPool>,
+    config: Arc<BotConfig>,
+) {
+    let (sender, replies) = mpsc::unbounded();
+    let manager = SessionManager::new(pool, config, sender);
+
+    let mut events = stream::select(
+        incoming
+            .map(Event::Message)
+            .chain(stream::iter([Event::Closed])),
+        replies.map(Event::Reply),
+    );
+    while let Some(event) = events.next().await {
+        match event {
+            Event::Message(msg) => manager.handle(&msg.session, msg.message),
+            Event::Reply(reply) => outgoing.send(reply).await.unwrap(),
+            Event::Closed => break,
+        }
+    }
+
+    // closing the sessions ends the stream of replies once the ones already sent are through
+    drop(manager);
+    while let Some(event) = events.next().await {
+        if let Event::Reply(reply) = event {
+            outgoing.send(reply).await.unwrap();
+        }
+    }
+}
+
+enum Event {
+    Message(Envelope<FrontendMessage>),
+    Reply(Envelope<BotMessage>),
+    Closed,
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn envelope(msg: Value) -> Envelope<FrontendMessage> {
+        serde_json::from_value(msg).unwrap()
+    }
+
+    /// The session and type of each message.
+    fn sent(messages: impl IntoIterator<Item = Envelope<BotMessage>>) -> Vec<(String, String)> {
+        messages
+            .into_iter()
+            .map(|msg| {
+                let msg = serde_json::to_value(msg).unwrap();
+                let field = |name: &str| msg[name].as_str().unwrap().to_owned();
+                (field("session"), field("type"))
+            })
+            .collect()
+    }
+
+    fn expected(messages: &[(&str, &str)]) -> Vec<(String, String)> {
+        messages
+            .iter()
+            .map(|&(session, kind)| (session.to_owned(), kind.to_owned()))
+            .collect()
+    }
+
+    #[test]
+    fn envelopes_flatten_the_message() {
+        let msg = envelope(json!({"session": "a", "type": "plan", "depth": 3}));
+        assert_eq!(msg.session, "a");
+        assert!(matches!(msg.message, FrontendMessage::Plan { depth: 3 }));
+
+        let msg = envelope(json!({"session": "b", "type": "not_a_message"}));
+        assert!(matches!(msg.message, FrontendMessage::Unknown));
+
+        let reply = Envelope {
+            session: "c".to_owned(),
+            message: BotMessage::Ready,
+        };
+        assert_eq!(
+            serde_json::to_value(reply).unwrap(),
+            json!({"session": "c", "type": "ready"})
+        );
+    }
+
+    #[test]
+    fn manager_keeps_sessions_apart() {
+        let pool = Arc::new(WorkerPool::new(1));
+        let (sender, mut replies) = mpsc::unbounded();
+        let manager = SessionManager::new(pool, Arc::new(BotConfig::default()), sender);
+
+        manager.handle("a", FrontendMessage::Rules(Default::default()));
+        manager.handle("b", FrontendMessage::Rules(Default::default()));
+        manager.handle("a", FrontendMessage::Rules(Default::default()));
+        assert_eq!(manager.len(), 2);
+
+        manager.handle("a", FrontendMessage::Quit);
+        assert_eq!(manager.len(), 1);
+        assert!(!manager.close("a"));
+        assert!(manager.close("b"));
+        assert!(manager.is_empty());
+
+        let mut messages = vec![];
+        while let Ok(Some(msg)) = replies.try_next() {
+            messages.push(msg);
+        }
+        assert_eq!(
+            sent(messages),
+            expected(&[
+                ("a", "info"),
+                ("a", "ready"),
+                ("b", "info"),
+                ("b", "ready"),
+                ("a", "ready"),
+            ])
+        );
+    }
+
+    #[test]
+    fn multiplexed_sessions_reply_until_input_ends() {
+        let incoming = stream::iter([
+            envelope(json!({"session": "a", "type": "rules"})),
+            envelope(json!({"session": "b", "type": "rules"})),
+            envelope(json!({"session": "a", "type": "quit"})),
+            // a session that quit starts over as a new one
+            envelope(json!({"session": "a", "type": "rules", "hold": "disabled"})),
+        ]);
+        let (outgoing, replies) = mpsc::unbounded();
+        let outgoing = outgoing.sink_map_err(|_| -> Infallible { unreachable!() });
+        let pool = Arc::new(WorkerPool::new(1));
+        block_on(run_multiplexed(
+            incoming,
+            outgoing,
+            pool,
+            Arc::new(BotConfig::default()),
+        ));
+
+        let messages = block_on(replies.collect::<Vec<_>>());
+        assert_eq!(
+            sent(messages),
+            expected(&[
+                ("a", "info"),
+                ("a", "ready"),
+                ("b", "info"),
+                ("b", "ready"),
+                ("a", "info"),
+                ("a", "ready"),
+            ])
+        );
+    }
+}
@@ -1,32 +1,51 @@
-use web_time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
 use async_lock::Mutex as AsyncMutex;
 use async_lock::RwLock as AsyncRwLock;
 use gloo_timers::future::TimeoutFuture;
+use web_time::Instant;
 
-use parking_lot::{Condvar, Mutex, RwLock};
+use parking_lot::{Condvar, Mutex, RwLock, RwLockWriteGuard};
 
-use crate::bot::{Bot, Statistics};
-use crate::data::{Piece, Placement};
+use futures::channel::mpsc::UnboundedSender;
+
+use crate::bot::{Bot, Limits, PlannedMove, Recovery, Statistics, TreeNode};
+use crate::data::{GameState, GarbageQueue, Piece, Placement};
 use crate::tbp::MoveInfo;
 
 pub struct BotSyncronizer {
     state: Mutex<State>,
-    blocker: Condvar,
+    signal: Arc<Signal>,
     bot: RwLock<Option<Bot>>,
+    on_budget_spent: Box<dyn Fn(&BotSyncronizer) + Send + Sync>,
 }
 
 impl BotSyncronizer {
-    pub fn new() -> Self {
+    /// `on_budget_spent` is called from a worker when the search stops for a move and a
+    /// suggestion should be sent without being asked for. See
+    /// [`BotSyncronizer::take_auto_suggestion`].
+    fn new(
+        signal: Arc<Signal>,
+        on_budget_spent: Box<dyn Fn(&BotSyncronizer) + Send + Sync>,
+    ) -> Self {
         BotSyncronizer {
             state: Mutex::new(State {
                 stats: Default::default(),
                 last_advance: Instant::now(),
-                node_limit: u64::MAX,
+                limits: Limits::default(),
                 start: Instant::now(),
                 nodes_since_start: 0,
+                suggested: false,
+                announced: false,
+                paused: false,
+                out_of_memory: false,
+                shutdown: false,
             }),
-            blocker: Condvar::new(),
+            signal,
             bot: RwLock::new(None),
+            on_budget_spent,
         }
     }
 
@@ -35,18 +54,33 @@ impl BotSyncronizer {
         state.stats = Default::default();
         state.nodes_since_start = 0;
         state.start = Instant::now();
+        state.last_advance = Instant::now();
+        state.limits = initial_state.config().limits;
+        state.suggested = false;
+        state.announced = false;
+        state.out_of_memory = false;
         *self.bot.write() = Some(initial_state);
-        self.blocker.notify_all();
+        self.signal.notify();
+    }
+
+    pub fn set_limits(&self, limits: Limits) {
+        let mut state = self.state.lock();
+        state.limits = limits;
+        state.out_of_memory = false;
+        drop(state);
+        self.signal.notify();
     }
 
     pub fn stop(&self) {
-        *self.bot.write() = None;
+        *self.write_bot() = None;
     }
 
     pub fn suggest(&self) -> Option<(Vec<Placement>, MoveInfo)> {
+        // the state is always locked before the bot, since workers may be suggesting moves
+        let mut state = self.state.lock();
         let bot = self.bot.read();
         bot.as_ref().map(|bot| {
-            let state = self.state.lock();
+            state.suggested = true;
             let suggestion = bot.suggest();
             let info = MoveInfo {
                 nodes: state.stats.nodes,
@@ -54,76 +88,301 @@ impl BotSyncronizer {
                 extra: format!(
                     "{:.1}% of selections expanded, overall speed: {:.1} Mnps",
                     state.stats.expansions as f64 / state.stats.selections as f64 * 100.0,
-                    state.nodes_since_start as f64 / state.start.elapsed().as_secs_f64() / 1_000_000.0
-                )
+                    state.nodes_since_start as f64
+                        / state.start.elapsed().as_secs_f64()
+                        / 1_000_000.0
+                ),
+                paths: suggestion.iter().map(|&mv| bot.path(mv)).collect(),
+                perfect_clear_probability: bot.perfect_clear_probability(),
+                dig: bot.dig_progress(),
+                candidates: bot.candidates(bot.config().candidates),
+                memory: state.stats.memory,
             };
             (suggestion, info)
         })
     }
 
-    pub fn advance(&self, mv: Placement) {
+    /// The suggestion to send once the search budget is spent, unless one was already sent for
+    /// this move.
+    pub fn take_auto_suggestion(&self) -> Option<(Vec<Placement>, MoveInfo)> {
+        {
+            let state = self.state.lock();
+            if state.suggested || !matches!(state.budget(), Budget::Spent) {
+                return None;
+            }
+        }
+        self.suggest()
+    }
+
+    pub fn advance(&self, mv: Placement) -> Option<Recovery> {
         let mut state = self.state.lock();
-        state.stats = Default::default();
-        state.last_advance = Instant::now();
-        let mut bot = self.bot.write();
-        if let Some(bot) = &mut *bot {
-            bot.advance(mv);
+        state.new_move();
+        let recovery = self.write_bot().as_mut().and_then(|bot| bot.advance(mv));
+        self.signal.notify();
+        recovery
+    }
+
+    /// See [`Bot::resync`]. Returns `None` if there is no bot to resync.
+    pub fn resync(&self, position: GameState, queue: &[Piece]) -> Option<Option<Recovery>> {
+        let mut state = self.state.lock();
+        let recovery = self
+            .write_bot()
+            .as_mut()
+            .map(|bot| bot.resync(position, queue))?;
+        if recovery.is_some() {
+            state.new_move();
         }
-        self.blocker.notify_all();
+        self.signal.notify();
+        Some(recovery)
     }
 
     pub fn new_piece(&self, piece: Piece) {
-        let mut bot = self.bot.write();
+        let mut bot = self.write_bot();
         if let Some(bot) = &mut *bot {
             bot.new_piece(piece);
         }
-        self.blocker.notify_all();
+        self.signal.notify();
     }
 
-    pub fn work_loop(&self) {
+    pub fn set_garbage(&self, garbage: GarbageQueue) {
+        let mut bot = self.write_bot();
+        if let Some(bot) = &mut *bot {
+            bot.set_garbage(garbage);
+        }
+        self.signal.notify();
+    }
+
+    pub fn plan(&self, depth: usize) -> Option<Vec<PlannedMove>> {
+        self.bot.read().as_ref().map(|bot| bot.plan(depth))
+    }
+
+    pub fn tree(&self, depth: usize, breadth: usize) -> Option<TreeNode> {
+        self.bot.read().as_ref().map(|bot| bot.tree(depth, breadth))
+    }
+
+    /// Stops the search without dropping the bot, until [`BotSyncronizer::resume`] is called.
+    pub fn pause(&self) {
+        self.state.lock().paused = true;
+        self.interrupt();
+    }
+
+    pub fn resume(&self) {
+        self.state.lock().paused = false;
+        self.signal.notify();
+    }
+
+    fn interrupt(&self) {
+        if let Some(bot) = &*self.bot.read() {
+            bot.interrupt();
+        }
+    }
+
+    fn write_bot(&self) -> RwLockWriteGuard<'_, Option<Bot>> {
+        self.interrupt();
+        self.bot.write()
+    }
+
+    /// Does one batch of work if there is any to do.
+    fn work(&self) -> Work {
+        let mut state = self.state.lock();
+        if state.paused {
+            return Work::Idle(None);
+        }
+        match state.budget() {
+            Budget::Remaining => {}
+            Budget::Hold(until) => return Work::Idle(Some(until)),
+            Budget::Spent => {
+                // only one worker needs to announce that this move is done
+                let announce = state.limits.auto_suggest && !state.suggested && !state.announced;
+                state.announced = true;
+                drop(state);
+                if announce {
+                    (self.on_budget_spent)(self);
+                }
+                return Work::Idle(None);
+            }
+        }
+        let bot_guard = self.bot.read();
+        let bot = match &*bot_guard {
+            Some(bot) => bot,
+            None => return Work::Idle(None),
+        };
+
+        drop(state);
+        let new_stats = bot.do_work();
+        drop(bot_guard);
+
         let mut state = self.state.lock();
+        state.stats.accumulate(new_stats);
+        state.nodes_since_start += new_stats.nodes;
+        if let Some(budget) = state.memory_budget() {
+            if new_stats.memory > budget as u64 {
+                let mut bot = self.write_bot();
+                if let Some(bot) = &mut *bot {
+                    state.out_of_memory = !bot.fit_memory(budget);
+                    state.stats.memory = bot.memory() as u64;
+                }
+            }
+        }
+        Work::Done
+    }
+}
+
+enum Work {
+    Done,
+    /// There is nothing to do until something changes, or until the given time.
+    Idle(Option<Instant>),
+}
+
+/// Wakes idle workers when a bot they could be working on changes.
+#[derive(Default)]
+struct Signal {
+    generation: Mutex<u64>,
+    condvar: Condvar,
+}
+
+impl Signal {
+    fn notify(&self) {
+        *self.generation.lock() += 1;
+        self.condvar.notify_all();
+    }
+}
+
+/// Search threads shared by any number of bots. Workers take turns between the bots that have
+/// work to do, a batch at a time. The threads are stopped and joined when the pool is dropped.
+pub struct WorkerPool {
+    shared: Arc<PoolShared>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+#[derive(Default)]
+struct PoolShared {
+    bots: RwLock<Vec<Arc<BotSyncronizer>>>,
+    next: AtomicUsize,
+    signal: Arc<Signal>,
+    shutdown: AtomicBool,
+}
+
+impl WorkerPool {
+    pub fn new(threads: usize) -> Self {
+        let shared = Arc::new(PoolShared::default());
+        let threads = (0..threads)
+            .map(|i| {
+                let shared = shared.clone();
+                std::thread::Builder::new()
+                    .name(format!("worker {}", i))
+                    .spawn(move || shared.work_loop())
+                    .unwrap()
+            })
+            .collect();
+        WorkerPool { shared, threads }
+    }
+
+    /// Adds a bot for the workers to search with.
+    pub fn add(
+        &self,
+        on_budget_spent: impl Fn(&BotSyncronizer) + Send + Sync + 'static,
+    ) -> Arc<BotSyncronizer> {
+        let bot = Arc::new(BotSyncronizer::new(
+            self.shared.signal.clone(),
+            Box::new(on_budget_spent),
+        ));
+        self.shared.bots.write().push(bot.clone());
+        self.shared.signal.notify();
+        bot
+    }
+
+    pub fn remove(&self, bot: &Arc<BotSyncronizer>) {
+        self.shared.bots.write().retain(|b| !Arc::ptr_eq(b, bot));
+        bot.interrupt();
+    }
+}
+
+impl PoolShared {
+    fn work_loop(&self) {
         loop {
-            if state.stats.nodes > state.node_limit {
-                self.blocker.wait(&mut state);
-                continue;
+            let generation = *self.signal.generation.lock();
+            if self.shutdown.load(Ordering::Relaxed) {
+                return;
             }
-            let bot_guard = self.bot.read();
-            let bot = match &*bot_guard {
-                Some(bot) => bot,
-                None => {
-                    drop(bot_guard);
-                    self.blocker.wait(&mut state);
-                    continue;
+
+            let bots = self.bots.read().clone();
+            let first = self.next.fetch_add(1, Ordering::Relaxed);
+            let mut wake_at: Option<Instant> = None;
+            let mut worked = false;
+            for i in 0..bots.len() {
+                match bots[(first + i) % bots.len()].work() {
+                    Work::Done => {
+                        worked = true;
+                        break;
+                    }
+                    Work::Idle(until) => {
+                        wake_at = match (wake_at, until) {
+                            (Some(a), Some(b)) => Some(a.min(b)),
+                            (a, b) => a.or(b),
+                        };
+                    }
                 }
-            };
+            }
+            drop(bots);
 
-            drop(state);
-            let new_stats = bot.do_work();
-            drop(bot_guard);
+            if !worked {
+                let mut current = self.signal.generation.lock();
+                if *current == generation {
+                    match wake_at {
+                        Some(until) => {
+                            // the condvar takes a std Instant, which web_time's isn't on wasm
+                            let timeout = until.saturating_duration_since(Instant::now());
+                            self.signal.condvar.wait_for(&mut current, timeout);
+                        }
+                        None => self.signal.condvar.wait(&mut current),
+                    }
+                }
+            }
+        }
+    }
+}
 
-            state = self.state.lock();
-            state.stats.accumulate(new_stats);
-            state.nodes_since_start += new_stats.nodes;
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.shutdown.store(true, Ordering::Relaxed);
+        for bot in &*self.shared.bots.read() {
+            bot.interrupt();
+        }
+        self.shared.signal.notify();
+        for thread in self.threads.drain(..) {
+            // a worker that panicked has nothing left to clean up
+            let _ = thread.join();
         }
     }
 }
 
+/// How long the WASM worker sleeps for when it has nothing to do, in milliseconds.
+const IDLE_SLEEP: u32 = 10;
+
 pub struct BotSyncronizerWASM {
     state: AsyncMutex<State>,
     bot: AsyncRwLock<Option<Bot>>,
+    budget_spent: UnboundedSender<()>,
 }
 
 impl BotSyncronizerWASM {
-    pub fn new() -> Self {
+    pub fn new(budget_spent: UnboundedSender<()>) -> Self {
         BotSyncronizerWASM {
             state: AsyncMutex::new(State {
                 stats: Default::default(),
                 last_advance: Instant::now(),
-                node_limit: u64::MAX,
+                limits: Limits::default(),
                 start: Instant::now(),
                 nodes_since_start: 0,
+                suggested: false,
+                announced: false,
+                paused: false,
+                out_of_memory: false,
+                shutdown: false,
             }),
             bot: AsyncRwLock::new(None),
+            budget_spent,
         }
     }
 
@@ -132,17 +391,30 @@ impl BotSyncronizerWASM {
         state.stats = Default::default();
         state.nodes_since_start = 0;
         state.start = Instant::now();
+        state.last_advance = Instant::now();
+        state.limits = initial_state.config().limits;
+        state.suggested = false;
+        state.announced = false;
+        state.out_of_memory = false;
         *self.bot.write().await = Some(initial_state);
     }
 
+    pub async fn set_limits(&self, limits: Limits) {
+        let mut state = self.state.lock().await;
+        state.limits = limits;
+        state.out_of_memory = false;
+    }
+
     pub async fn stop(&self) {
         *self.bot.write().await = None;
     }
 
     pub async fn suggest(&self) -> Option<(Vec<Placement>, MoveInfo)> {
+        // the state is always locked before the bot, like the native version
+        let mut state = self.state.lock().await;
         let bot = self.bot.read().await;
-        if let Some(bot) = bot.as_ref() {
-            let state = self.state.lock().await;
+        bot.as_ref().map(|bot| {
+            state.suggested = true;
             let suggestion = bot.suggest();
             let info = MoveInfo {
                 nodes: state.stats.nodes,
@@ -150,24 +422,69 @@ impl BotSyncronizerWASM {
                 extra: format!(
                     "{:.1}% of selections expanded, overall speed: {:.1} Mnps",
                     state.stats.expansions as f64 / state.stats.selections as f64 * 100.0,
-                    state.nodes_since_start as f64 / state.start.elapsed().as_secs_f64() / 1_000_000.0
-                )
+                    state.nodes_since_start as f64
+                        / state.start.elapsed().as_secs_f64()
+                        / 1_000_000.0
+                ),
+                paths: suggestion.iter().map(|&mv| bot.path(mv)).collect(),
+                perfect_clear_probability: bot.perfect_clear_probability(),
+                dig: bot.dig_progress(),
+                candidates: bot.candidates(bot.config().candidates),
+                memory: state.stats.memory,
             };
-            Some((suggestion, info))
-        }
-        else {
-            None
+            (suggestion, info)
+        })
+    }
+
+    pub async fn pause(&self) {
+        self.state.lock().await.paused = true;
+    }
+
+    pub async fn resume(&self) {
+        self.state.lock().await.paused = false;
+    }
+
+    pub async fn shutdown(&self) {
+        self.state.lock().await.shutdown = true;
+    }
+
+    pub async fn take_auto_suggestion(&self) -> Option<(Vec<Placement>, MoveInfo)> {
+        {
+            let state = self.state.lock().await;
+            if state.suggested || !matches!(state.budget(), Budget::Spent) {
+                return None;
+            }
         }
+        self.suggest().await
     }
 
-    pub async fn advance(&self, mv: Placement) {
+    pub async fn plan(&self, depth: usize) -> Option<Vec<PlannedMove>> {
+        self.bot.read().await.as_ref().map(|bot| bot.plan(depth))
+    }
+
+    pub async fn tree(&self, depth: usize, breadth: usize) -> Option<TreeNode> {
+        self.bot
+            .read()
+            .await
+            .as_ref()
+            .map(|bot| bot.tree(depth, breadth))
+    }
+
+    pub async fn advance(&self, mv: Placement) -> Option<Recovery> {
         let mut state = self.state.lock().await;
-        state.stats = Default::default();
-        state.last_advance = Instant::now();
+        state.new_move();
         let mut bot = self.bot.write().await;
-        if let Some(bot) = &mut *bot {
-            bot.advance(mv);
+        bot.as_mut().and_then(|bot| bot.advance(mv))
+    }
+
+    pub async fn resync(&self, position: GameState, queue: &[Piece]) -> Option<Option<Recovery>> {
+        let mut state = self.state.lock().await;
+        let mut bot = self.bot.write().await;
+        let recovery = bot.as_mut().map(|bot| bot.resync(position, queue))?;
+        if recovery.is_some() {
+            state.new_move();
         }
+        Some(recovery)
     }
 
     pub async fn new_piece(&self, piece: Piece) {
@@ -177,6 +494,13 @@ impl BotSyncronizerWASM {
         }
     }
 
+    pub async fn set_garbage(&self, garbage: GarbageQueue) {
+        let mut bot = self.bot.write().await;
+        if let Some(bot) = &mut *bot {
+            bot.set_garbage(garbage);
+        }
+    }
+
     pub async fn work_loop(&self) {
         let mut cnt = 0;
         loop {
@@ -185,32 +509,208 @@ impl BotSyncronizerWASM {
                 TimeoutFuture::new(1).await;
             }
             let mut state = self.state.lock().await;
-            if state.stats.nodes > state.node_limit {
+            if state.shutdown {
+                return;
+            }
+            if state.paused {
                 drop(state);
+                TimeoutFuture::new(IDLE_SLEEP).await;
                 continue;
             }
+            match state.budget() {
+                Budget::Remaining => {}
+                Budget::Hold(_) => {
+                    drop(state);
+                    TimeoutFuture::new(IDLE_SLEEP).await;
+                    continue;
+                }
+                Budget::Spent => {
+                    if state.limits.auto_suggest && !state.suggested && !state.announced {
+                        state.announced = true;
+                        let _ = self.budget_spent.unbounded_send(());
+                    }
+                    drop(state);
+                    TimeoutFuture::new(IDLE_SLEEP).await;
+                    continue;
+                }
+            }
             let bot_guard = self.bot.read().await;
             let bot = match &*bot_guard {
                 Some(bot) => bot,
                 None => {
                     drop(bot_guard);
                     drop(state);
+                    TimeoutFuture::new(IDLE_SLEEP).await;
                     continue;
                 }
             };
             let new_stats = bot.do_work();
             state.stats.accumulate(new_stats);
             state.nodes_since_start += new_stats.nodes;
+            drop(bot_guard);
+            if let Some(budget) = state.memory_budget() {
+                if new_stats.memory > budget as u64 {
+                    let mut bot = self.bot.write().await;
+                    if let Some(bot) = &mut *bot {
+                        state.out_of_memory = !bot.fit_memory(budget);
+                        state.stats.memory = bot.memory() as u64;
+                    }
+                }
+            }
         }
     }
 }
 
-
 #[derive(Copy, Clone, Debug)]
 struct State {
     stats: Statistics,
     last_advance: Instant,
-    node_limit: u64,
+    limits: Limits,
     start: Instant,
     nodes_since_start: u64,
+    /// Whether a suggestion was already sent for the current move.
+    suggested: bool,
+    /// Whether a worker already announced that the search budget is spent for the current move.
+    announced: bool,
+    paused: bool,
+    /// Whether the search tree can't be pruned to fit in the memory limit, so it can't grow.
+    out_of_memory: bool,
+    shutdown: bool,
+}
+
+enum Budget {
+    Remaining,
+    /// The search is done, but the move shouldn't be sent before the minimum think time.
+    Hold(Instant),
+    Spent,
+}
+
+impl State {
+    /// Starts counting the search for a new move.
+    fn new_move(&mut self) {
+        self.stats = Default::default();
+        self.last_advance = Instant::now();
+        self.suggested = false;
+        self.announced = false;
+        self.out_of_memory = false;
+    }
+
+    fn budget(&self) -> Budget {
+        let limits = &self.limits;
+        let elapsed = self.last_advance.elapsed().as_millis() as u64;
+        if self.out_of_memory || limits.max_time.is_some_and(|t| elapsed >= t) {
+            return Budget::Spent;
+        }
+        let done = limits.nodes.is_some_and(|n| self.stats.nodes >= n)
+            || limits.time.is_some_and(|t| elapsed >= t);
+        if !done {
+            return Budget::Remaining;
+        }
+        match limits.min_time {
+            Some(t) if elapsed < t => {
+                Budget::Hold(self.last_advance + std::time::Duration::from_millis(t))
+            }
+            _ => Budget::Spent,
+        }
+    }
+
+    /// The memory limit in bytes, if there is one.
+    fn memory_budget(&self) -> Option<usize> {
+        self.limits
+            .memory
+            .map(|mb| (mb as usize).saturating_mul(1 << 20))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use enumset::EnumSet;
+
+    use super::*;
+    use crate::bot::{BotConfig, BotOptions};
+    use crate::data::Board;
+
+    /// A freestyle bot on an empty board.
+    fn new_bot(limits: Limits) -> Bot {
+        let mut config = BotConfig::default();
+        config.perfect_clear.enabled = false;
+        config.limits = limits;
+        let root = GameState {
+            board: Board::new(10),
+            bag: EnumSet::all(),
+            reserve: Piece::T,
+            back_to_back: 0,
+            combo: 0,
+            garbage: GarbageQueue::default(),
+            hold_locked: false,
+            hold_empty: false,
+            garbage_rows: 0,
+        };
+        let options = BotOptions {
+            speculate: true,
+            config: Arc::new(config),
+        };
+        Bot::new(options, root, &[Piece::I, Piece::O, Piece::L])
+    }
+
+    #[test]
+    fn suggests_once_the_budget_is_spent() {
+        let pool = WorkerPool::new(2);
+        let (sender, suggestions) = mpsc::channel();
+        let bot = pool.add(move |bot| {
+            if let Some((moves, _)) = bot.take_auto_suggestion() {
+                sender.send(moves).unwrap();
+            }
+        });
+        bot.start(new_bot(Limits {
+            time: Some(50),
+            auto_suggest: true,
+            ..Default::default()
+        }));
+
+        let wait = Duration::from_secs(30);
+        let moves = suggestions.recv_timeout(wait).unwrap();
+        assert!(!moves.is_empty());
+        // the suggestion is sent once for each move
+        assert!(suggestions
+            .recv_timeout(Duration::from_millis(200))
+            .is_err());
+        bot.advance(moves[0]);
+        assert!(suggestions.recv_timeout(wait).is_ok());
+
+        // dropping the pool joins both workers
+        pool.remove(&bot);
+        drop(pool);
+        assert_eq!(Arc::strong_count(&bot), 1);
+    }
+
+    #[test]
+    fn pausing_stops_the_search_until_resumed() {
+        let pool = WorkerPool::new(1);
+        let bot = pool.add(|_| {});
+        bot.start(new_bot(Limits::default()));
+        let nodes = || bot.suggest().unwrap().1.nodes;
+        let searched_past = |count| {
+            let start = Instant::now();
+            while nodes() <= count {
+                assert!(start.elapsed() < Duration::from_secs(30));
+                std::thread::sleep(Duration::from_millis(10));
+            }
+        };
+        searched_past(0);
+
+        bot.pause();
+        // let the batch that was interrupted finish
+        std::thread::sleep(Duration::from_millis(100));
+        let paused = nodes();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(nodes(), paused);
+
+        bot.resume();
+        searched_past(paused);
+        pool.remove(&bot);
+    }
 }
@@ -1,14 +1,19 @@
 use enumset::{EnumSet, EnumSetType};
 use serde::{Deserialize, Serialize};
 
-use crate::data::{Board, Piece, Placement};
+use crate::bot::{Candidate, DigProgress, Limits, PlannedMove, Recovery, TreeNode};
+use crate::data::{Board, Garbage, Piece, Placement, MAX_WIDTH};
+use crate::movegen::{Handling, Path, RotationSystem};
+use crate::rules::{AttackRules, BoardRules, GameRules, Gravity, HoldMode, Rotate180, SpinRule};
 
 #[derive(Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum FrontendMessage {
-    Rules,
+    Rules(Rules),
     Start(Start),
+    /// Corrects the bot's view of the game, keeping the search tree where possible.
+    Resync(Start),
     Play {
         #[serde(rename = "move")]
         mv: Placement,
@@ -17,7 +22,31 @@ pub enum FrontendMessage {
         piece: Piece,
     },
     Suggest,
+    /// Replaces the pending garbage. A change starts a new search, so this should only be sent
+    /// when the garbage queue actually changes.
+    Garbage {
+        garbage: Vec<PendingGarbage>,
+    },
+    /// Sets how much the bot thinks for each move, including the current one.
+    Limits(Limits),
+    /// Asks for the line the bot expects to be played.
+    Plan {
+        #[serde(default = "default_plan_depth")]
+        depth: usize,
+    },
+    /// Asks for an export of the search tree.
+    Tree {
+        #[serde(default = "default_tree_depth")]
+        depth: usize,
+        #[serde(default = "default_tree_breadth")]
+        breadth: usize,
+        #[serde(default)]
+        format: TreeFormat,
+    },
     Stop,
+    /// Stops thinking until `resume` is received, keeping the search tree.
+    Pause,
+    Resume,
     Quit,
     #[serde(other)]
     Unknown,
@@ -37,21 +66,80 @@ pub enum BotMessage {
     Suggestion {
         moves: Vec<Placement>,
         move_info: MoveInfo,
+    },
+    Plan {
+        moves: Vec<PlannedMove>,
+    },
+    Tree {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        tree: Option<TreeNode>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        dot: Option<String>,
+    },
+    /// Sent after a move the bot wasn't prepared for is played.
+    Recovered {
+        recovery: Recovery,
+    },
+}
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TreeFormat {
+    #[default]
+    Json,
+    Dot,
+}
+
+impl BotMessage {
+    pub fn info() -> Self {
+        BotMessage::Info {
+            name: "Cold Clear 2",
+            version: concat!(env!("CARGO_PKG_VERSION"), " ", env!("GIT_HASH")),
+            author: "MinusKelvin",
+            features: &[],
+        }
     }
 }
 
-#[derive(Deserialize)]
+/// Game rules requested by the frontend. Rules that are left out keep their configured value.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct Rules {
+    pub attack: Option<AttackRules>,
+    pub rotation_system: Option<RotationSystem>,
+    pub rotate_180: Option<Rotate180>,
+    pub spins: Option<SpinRule>,
+    pub handling: Option<Handling>,
+    pub gravity: Option<Gravity>,
+    pub board: Option<BoardRules>,
+    pub hold: Option<HoldMode>,
+}
+
+#[derive(Deserialize, Clone)]
 pub struct Start {
-    pub board: Board,
+    /// Rows from the bottom up. Rows at the bottom with `G` cells are treated as garbage.
+    pub board: Vec<Vec<Option<char>>>,
     pub queue: Vec<Piece>,
     pub hold: Option<Piece>,
     pub combo: u32,
     pub back_to_back: bool,
     #[serde(default)]
     pub randomizer: Randomizer,
+    #[serde(default)]
+    pub garbage: Vec<PendingGarbage>,
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Clone, Copy)]
+pub struct PendingGarbage {
+    pub amount: u32,
+    /// Number of pieces the bot places before the garbage is ready to land.
+    #[serde(default)]
+    pub delay: u32,
+    #[serde(default)]
+    pub column: Option<u8>,
+}
+
+#[derive(Deserialize, Clone, Default)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum Randomizer {
@@ -60,36 +148,113 @@ pub enum Randomizer {
         bag_state: EnumSet<Piece>,
     },
     #[serde(other)]
+    #[default]
     Unknown,
 }
 
-impl Default for Randomizer {
-    fn default() -> Self {
-        Self::Unknown
-    }
-}
-
 #[derive(Serialize)]
 pub struct MoveInfo {
     pub nodes: u64,
     pub nps: f64,
     pub extra: String,
+    /// Inputs to play each suggested move, or null if the bot could not find them.
+    pub paths: Vec<Option<Path>>,
+    /// Chance that the first suggested move leads to a perfect clear, when looking for one.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub perfect_clear_probability: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub dig: Option<DigProgress>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub candidates: Vec<Candidate>,
+    /// Approximate number of bytes used by the search tree.
+    pub memory: u64,
 }
 
-impl From<Vec<[Option<char>; 10]>> for Board {
-    fn from(v: Vec<[Option<char>; 10]>) -> Self {
-        let mut cols = [0; 10];
-        for x in 0..10 {
-            for y in 0..40 {
-                if v[y][x].is_some() {
+impl From<Vec<Vec<Option<char>>>> for Board {
+    fn from(v: Vec<Vec<Option<char>>>) -> Self {
+        let mut cols = [0; MAX_WIDTH];
+        let width = v.iter().map(Vec::len).max().unwrap_or(10).min(MAX_WIDTH);
+        for (y, row) in v.iter().enumerate().take(40) {
+            for (x, cell) in row.iter().enumerate().take(MAX_WIDTH) {
+                if cell.is_some() {
                     cols[x] |= 1 << y;
                 }
             }
         }
-        Board { cols }
+        Board::from_cols(&cols[..width])
     }
 }
 
+/// Rows from the bottom up to the highest filled row, with filled cells marked `G`.
+impl From<Board> for Vec<Vec<Option<char>>> {
+    fn from(board: Board) -> Self {
+        let height = board
+            .cols()
+            .iter()
+            .map(|&c| 64 - c.leading_zeros())
+            .max()
+            .unwrap_or(0);
+        (0..height as i8)
+            .map(|y| {
+                (0..board.width())
+                    .map(|x| board.occupied((x, y)).then_some('G'))
+                    .collect()
+            })
+            .collect()
+    }
+}
+
+impl Rules {
+    pub fn apply(self, rules: &mut GameRules) {
+        if let Some(attack) = self.attack {
+            rules.attack = attack;
+        }
+        if let Some(rotation_system) = self.rotation_system {
+            rules.rotation_system = rotation_system;
+        }
+        if let Some(rotate_180) = self.rotate_180 {
+            rules.rotate_180 = rotate_180;
+        }
+        if let Some(spins) = self.spins {
+            rules.spins = spins;
+        }
+        if let Some(handling) = self.handling {
+            rules.handling = handling;
+        }
+        if let Some(gravity) = self.gravity {
+            rules.gravity = gravity;
+        }
+        if let Some(board) = self.board {
+            rules.board = board;
+        }
+        if let Some(hold) = self.hold {
+            rules.hold = hold;
+        }
+    }
+}
+
+impl From<PendingGarbage> for Garbage {
+    fn from(g: PendingGarbage) -> Self {
+        Garbage {
+            lines: g.amount.try_into().unwrap_or(u8::MAX),
+            delay: g.delay.try_into().unwrap_or(u8::MAX),
+            column: g.column,
+        }
+    }
+}
+
+fn default_plan_depth() -> usize {
+    16
+}
+
+fn default_tree_depth() -> usize {
+    3
+}
+
+fn default_tree_breadth() -> usize {
+    3
+}
+
 fn collect_enumset<'de, D, T>(de: D) -> Result<EnumSet<T>, D::Error>
 where
     D: serde::Deserializer<'de>,
@@ -97,3 +262,97 @@ where
 {
     Ok(Vec::<T>::deserialize(de)?.into_iter().collect())
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+    use crate::bot::TreeEdge;
+    use crate::data::{PieceLocation, Rotation, Spin};
+    use crate::tree_message;
+
+    fn placement() -> Placement {
+        Placement {
+            location: PieceLocation {
+                piece: Piece::T,
+                rotation: Rotation::South,
+                x: 4,
+                y: 2,
+            },
+            spin: Spin::Full,
+        }
+    }
+
+    fn board() -> Board {
+        Board::from_cols(&[0b1, 0b11, 0, 0, 0b111, 0, 0, 0, 0, 0b1])
+    }
+
+    fn read<T: for<'de> Deserialize<'de>>(value: &Value) -> T {
+        serde_json::from_value(value.clone()).unwrap()
+    }
+
+    #[test]
+    fn plan_messages_round_trip() {
+        let msg = BotMessage::Plan {
+            moves: vec![PlannedMove {
+                piece: Piece::T,
+                speculated: true,
+                mv: placement(),
+                eval: 1.5,
+                board: board(),
+            }],
+        };
+        let msg = serde_json::to_value(msg).unwrap();
+        assert_eq!(msg["type"], "plan");
+        let planned = &msg["moves"][0];
+        assert_eq!(read::<Piece>(&planned["piece"]), Piece::T);
+        assert_eq!(planned["speculated"], true);
+        assert_eq!(read::<Placement>(&planned["move"]), placement());
+        assert_eq!(planned["eval"], 1.5);
+        assert_eq!(read::<Board>(&planned["board"]), board());
+    }
+
+    #[test]
+    fn tree_messages_round_trip() {
+        let leaf = TreeNode {
+            board: board(),
+            eval: -2.0,
+            visits: 0,
+            expansions: 0,
+            children: vec![],
+        };
+        let tree = TreeNode {
+            board: Board::new(10),
+            eval: 3.0,
+            visits: 7,
+            expansions: 2,
+            children: vec![TreeEdge {
+                piece: Piece::T,
+                speculated: false,
+                mv: placement(),
+                eval: -2.0,
+                visits: 5,
+                node: leaf,
+            }],
+        };
+
+        let msg = serde_json::to_value(tree_message(tree.clone(), TreeFormat::Json)).unwrap();
+        assert_eq!(msg["type"], "tree");
+        assert!(msg.get("dot").is_none());
+        let root = &msg["tree"];
+        assert_eq!(read::<Board>(&root["board"]), Board::new(10));
+        assert_eq!(root["visits"], 7);
+        assert_eq!(root["expansions"], 2);
+        let edge = &root["children"][0];
+        assert_eq!(read::<Piece>(&edge["piece"]), Piece::T);
+        assert_eq!(read::<Placement>(&edge["move"]), placement());
+        assert_eq!(edge["visits"], 5);
+        assert_eq!(read::<Board>(&edge["node"]["board"]), board());
+        assert_eq!(edge["node"]["children"], Value::Array(vec![]));
+
+        let msg = serde_json::to_value(tree_message(tree.clone(), TreeFormat::Dot)).unwrap();
+        assert!(msg.get("tree").is_none());
+        assert_eq!(msg["dot"], tree.to_dot());
+    }
+}